@@ -0,0 +1,90 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+//
+// This file contains the native procedures every VM starts with.
+
+use crate::memory::ObjRef;
+use crate::object::{MapKey, Obj, ObjList, ObjMap, UNHASHABLE_KEY};
+use crate::value::Value;
+use crate::vm::VM;
+
+// Define the built-in procedures as globals of `vm`
+pub fn define_builtins(vm: &mut VM) -> () {
+    vm.define_native("len", 1, len);
+    vm.define_native("push", 2, push);
+    vm.define_native("pop", 1, pop);
+    vm.define_native("has", 2, has);
+    vm.define_native("remove", 2, remove);
+    vm.define_native("keys", 1, keys);
+}
+
+// Number of items in a list, entries in a map or characters in a string
+fn len(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if let Some(items) = vm.as_list(args[0]) {
+        return Ok(Value::Int(items.len() as i64));
+    }
+    if let Some(map) = vm.as_map_mut(args[0]) {
+        return Ok(Value::Int(map.entries.len() as i64));
+    }
+    match vm.as_str(args[0]) {
+        Some(chars) => Ok(Value::Int(chars.chars().count() as i64)),
+        None => Err("len expects a list, a map or a string.".to_string()),
+    }
+}
+
+// Append an item to the end of a list
+fn push(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let items = vm
+        .as_list_mut(args[0])
+        .ok_or_else(|| "push expects a list.".to_string())?;
+    items.push(args[1]);
+    Ok(Value::Nil)
+}
+
+// Remove the last item of a list and return it
+fn pop(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let items = vm
+        .as_list_mut(args[0])
+        .ok_or_else(|| "pop expects a list.".to_string())?;
+    items
+        .pop()
+        .ok_or_else(|| "Can't pop from an empty list.".to_string())
+}
+
+// Whether a map has an entry for a key
+fn has(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let key = map_key(vm, args[1])?;
+    let map = map(vm, args[0], "has")?;
+    Ok(Value::Bool(map.get(key).is_some()))
+}
+
+// Remove the entry of a key from a map, returning its value or `money` if there was none
+fn remove(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let key = map_key(vm, args[1])?;
+    let map = map(vm, args[0], "remove")?;
+    Ok(map.remove(key).unwrap_or(Value::Nil))
+}
+
+// List of the keys of a map, in the order they were inserted
+fn keys(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let keys = map(vm, args[0], "keys")?
+        .entries
+        .iter()
+        .map(|&(key, _)| key)
+        .collect();
+
+    // The keys are still referenced by the map, which is an argument on the stack
+    let list: ObjRef = vm.alloc(Obj::List(ObjList { items: keys }));
+    Ok(Value::Object(list))
+}
+
+// The map argument of the native `name`
+fn map<'v>(vm: &'v mut VM, value: Value, name: &str) -> Result<&'v mut ObjMap, String> {
+    vm.as_map_mut(value)
+        .ok_or_else(|| format!("{} expects a map.", name))
+}
+
+fn map_key(vm: &VM, value: Value) -> Result<MapKey, String> {
+    vm.map_key(value).ok_or_else(|| UNHASHABLE_KEY.to_string())
+}
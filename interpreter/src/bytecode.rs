@@ -0,0 +1,888 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+//
+// This file contains the binary `.vbc` format compiled scripts are saved in,
+// so they can be run again without scanning and parsing their source.
+//
+// A file starts with `MAGIC` and the little endian u16 `VERSION`, followed by
+// the top level script. A procedure is written as its name, arity, upvalues,
+// instructions with their line and constants. Procedures declared inside it
+// are constants, so they are written nested in the procedure declaring them.
+
+use crate::chunk::{Chunk, OpCode};
+use crate::memory::{Heap, ObjRef};
+use crate::object::{Obj, ObjFunction, Upvalue};
+use crate::value::Value;
+use crate::vm::VM;
+
+/// First bytes of every `.vbc` file.
+pub const MAGIC: &[u8; 4] = b"VBC\0";
+/// Version of the format, files of other versions are rejected.
+pub const VERSION: u16 = 1;
+
+// Tags of the constant kinds
+const CONSTANT_NIL: u8 = 0;
+const CONSTANT_FALSE: u8 = 1;
+const CONSTANT_TRUE: u8 = 2;
+const CONSTANT_NUMBER: u8 = 3;
+const CONSTANT_STRING: u8 = 4;
+const CONSTANT_FUNCTION: u8 = 5;
+const CONSTANT_INT: u8 = 6;
+
+// Deepest nesting of procedures a file may contain, so loading a
+// crafted file can't overflow the native stack
+const MAX_NESTING: usize = 256;
+
+/// Whether `bytes` look like a `.vbc` file rather than source code.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encodes the compiled script `function` and every procedure declared in it.
+pub fn serialize(function: ObjRef, heap: &Heap) -> Vec<u8> {
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.bytes.extend_from_slice(&VERSION.to_le_bytes());
+    writer.function(heap.as_function(function), heap);
+    writer.bytes
+}
+
+/// Decodes a script encoded by `serialize` and allocates it on the VM's heap.
+/// Truncated or corrupted files are reported as an error.
+pub fn deserialize(bytes: &[u8], vm: &mut VM) -> Result<ObjRef, String> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not a Vera byte code file.".to_string());
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(format!(
+            "Unsupported byte code version {}, expected {}.",
+            version, VERSION
+        ));
+    }
+
+    let script = reader.function(0)?;
+    if reader.position != bytes.len() {
+        return Err("Unexpected data after the script.".to_string());
+    }
+    // The script has no enclosing procedure to capture variables from
+    if !script.upvalues.is_empty() {
+        return Err("The script can't have upvalues.".to_string());
+    }
+    script.validate(0, 0)?;
+
+    // Only fully validated procedures reach the heap
+    Ok(script.allocate(vm, 0))
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) -> () {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) -> () {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) -> () {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) -> () {
+        self.u32(len as u32);
+    }
+
+    fn string(&mut self, chars: &str) -> () {
+        self.len(chars.len());
+        self.bytes.extend_from_slice(chars.as_bytes());
+    }
+
+    fn function(&mut self, function: &ObjFunction, heap: &Heap) -> () {
+        self.string(&function.name);
+        self.len(function.arity);
+
+        self.len(function.upvalues.len());
+        for upvalue in function.upvalues.iter() {
+            self.u8(upvalue.index);
+            self.u8(upvalue.is_local as u8);
+        }
+
+        let chunk = &function.chunk;
+        self.len(chunk.code.len());
+        for (&op, &line) in chunk.code.iter().zip(chunk.line.iter()) {
+            self.op(op);
+            self.len(line);
+        }
+
+        self.len(chunk.constants.array.len());
+        for &constant in chunk.constants.array.iter() {
+            self.constant(constant, heap);
+        }
+    }
+
+    fn constant(&mut self, value: Value, heap: &Heap) -> () {
+        match value {
+            Value::Nil => self.u8(CONSTANT_NIL),
+            Value::Bool(false) => self.u8(CONSTANT_FALSE),
+            Value::Bool(true) => self.u8(CONSTANT_TRUE),
+            Value::Number(number) => {
+                self.u8(CONSTANT_NUMBER);
+                self.bytes
+                    .extend_from_slice(&number.to_bits().to_le_bytes());
+            }
+            Value::Int(int) => {
+                self.u8(CONSTANT_INT);
+                self.bytes.extend_from_slice(&int.to_le_bytes());
+            }
+            // The parser only creates string and procedure constants
+            Value::Object(reference) => match heap.get(reference) {
+                Obj::String(string) => {
+                    self.u8(CONSTANT_STRING);
+                    self.string(&string.chars);
+                }
+                Obj::Function(function) => {
+                    self.u8(CONSTANT_FUNCTION);
+                    self.function(function, heap);
+                }
+                _ => unreachable!("Only strings and procedures are constants"),
+            },
+        }
+    }
+
+    // Every instruction is its tag followed by its operands
+    fn op(&mut self, op: OpCode) -> () {
+        match op {
+            OpCode::OpReturn => self.u8(0),
+            OpCode::OpConstant(i) => {
+                self.u8(1);
+                self.u8(i);
+            }
+            OpCode::OpConstantLong(i) => {
+                self.u8(2);
+                self.u32(i);
+            }
+            OpCode::OpDefineGlobal(i) => {
+                self.u8(3);
+                self.u8(i);
+            }
+            OpCode::OpDefineGlobalLong(i) => {
+                self.u8(4);
+                self.u32(i);
+            }
+            OpCode::OpGetGlobal(i) => {
+                self.u8(5);
+                self.u8(i);
+            }
+            OpCode::OpGetGlobalLong(i) => {
+                self.u8(6);
+                self.u32(i);
+            }
+            OpCode::OpSetGlobal(i) => {
+                self.u8(7);
+                self.u8(i);
+            }
+            OpCode::OpSetGlobalLong(i) => {
+                self.u8(8);
+                self.u32(i);
+            }
+            OpCode::OpGetLocal(slot) => {
+                self.u8(9);
+                self.u8(slot);
+            }
+            OpCode::OpSetLocal(slot) => {
+                self.u8(10);
+                self.u8(slot);
+            }
+            OpCode::OpNil => self.u8(11),
+            OpCode::OpTrue => self.u8(12),
+            OpCode::OpFalse => self.u8(13),
+            OpCode::OpNegate => self.u8(14),
+            OpCode::OpAdd => self.u8(15),
+            OpCode::OpSubtract => self.u8(16),
+            OpCode::OpMultiply => self.u8(17),
+            OpCode::OpDivide => self.u8(18),
+            OpCode::OpPow => self.u8(19),
+            OpCode::OpLeftShift => self.u8(20),
+            OpCode::OpRightShift => self.u8(21),
+            OpCode::OpNot => self.u8(22),
+            OpCode::OpEqual => self.u8(23),
+            OpCode::OpGreater => self.u8(24),
+            OpCode::OpLess => self.u8(25),
+            OpCode::OpPrint => self.u8(26),
+            OpCode::OpPop => self.u8(27),
+            OpCode::OpJumpIfFalse(jump) => {
+                self.u8(28);
+                self.u16(jump);
+            }
+            OpCode::OpJump(jump) => {
+                self.u8(29);
+                self.u16(jump);
+            }
+            OpCode::OpLoop(jump) => {
+                self.u8(30);
+                self.u16(jump);
+            }
+            OpCode::OpCall(arg_count) => {
+                self.u8(31);
+                self.u8(arg_count);
+            }
+            OpCode::OpClass(i) => {
+                self.u8(32);
+                self.u32(i);
+            }
+            OpCode::OpMethod(i) => {
+                self.u8(33);
+                self.u32(i);
+            }
+            OpCode::OpGetProperty(i) => {
+                self.u8(34);
+                self.u32(i);
+            }
+            OpCode::OpSetProperty(i) => {
+                self.u8(35);
+                self.u32(i);
+            }
+            OpCode::OpInvoke(i, arg_count) => {
+                self.u8(36);
+                self.u32(i);
+                self.u8(arg_count);
+            }
+            OpCode::OpClosure(i) => {
+                self.u8(37);
+                self.u32(i);
+            }
+            OpCode::OpGetUpvalue(slot) => {
+                self.u8(38);
+                self.u8(slot);
+            }
+            OpCode::OpSetUpvalue(slot) => {
+                self.u8(39);
+                self.u8(slot);
+            }
+            OpCode::OpCloseUpvalue => self.u8(40),
+            OpCode::OpBuildList(count) => {
+                self.u8(41);
+                self.u32(count);
+            }
+            OpCode::OpIndexGet => self.u8(42),
+            OpCode::OpIndexSet => self.u8(43),
+            OpCode::OpBuildMap(count) => {
+                self.u8(44);
+                self.u32(count);
+            }
+            OpCode::OpModulo => self.u8(45),
+            OpCode::OpBitAnd => self.u8(46),
+            OpCode::OpBitOr => self.u8(47),
+            OpCode::OpBitXor => self.u8(48),
+            OpCode::OpBitNot => self.u8(49),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize, // Index of the next byte to read
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.position < count {
+            return Err("Unexpected end of byte code file.".to_string());
+        }
+        let bytes = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "Invalid UTF-8 in string.".to_string())
+    }
+
+    // Read a procedure declared inside `depth` others
+    fn function(&mut self, depth: usize) -> Result<Prototype, String> {
+        if depth > MAX_NESTING {
+            return Err("Functions nested too deeply.".to_string());
+        }
+        let name = self.string()?;
+        let arity = self.len()?;
+
+        // Lengths aren't trusted for preallocation, a corrupted one could be huge
+        let mut upvalues = Vec::new();
+        for _ in 0..self.len()? {
+            let index = self.u8()?;
+            let is_local = match self.u8()? {
+                0 => false,
+                1 => true,
+                _ => return Err("Invalid upvalue.".to_string()),
+            };
+            upvalues.push(Upvalue { index, is_local });
+        }
+
+        let mut code = Vec::new();
+        let mut lines = Vec::new();
+        for _ in 0..self.len()? {
+            code.push(self.op()?);
+            lines.push(self.len()?);
+        }
+
+        let mut constants = Vec::new();
+        for _ in 0..self.len()? {
+            constants.push(self.constant(depth)?);
+        }
+
+        Ok(Prototype {
+            name,
+            arity,
+            upvalues,
+            code,
+            lines,
+            constants,
+        })
+    }
+
+    fn constant(&mut self, depth: usize) -> Result<Constant, String> {
+        let constant = match self.u8()? {
+            CONSTANT_NIL => Constant::Value(Value::Nil),
+            CONSTANT_FALSE => Constant::Value(Value::Bool(false)),
+            CONSTANT_TRUE => Constant::Value(Value::Bool(true)),
+            CONSTANT_NUMBER => {
+                let bytes = self.take(8)?;
+                let bits = u64::from_le_bytes(bytes.try_into().expect("Took 8 bytes"));
+                Constant::Value(Value::Number(f64::from_bits(bits)))
+            }
+            CONSTANT_INT => {
+                let bytes = self.take(8)?;
+                let int = i64::from_le_bytes(bytes.try_into().expect("Took 8 bytes"));
+                Constant::Value(Value::Int(int))
+            }
+            CONSTANT_STRING => Constant::String(self.string()?),
+            CONSTANT_FUNCTION => Constant::Function(self.function(depth + 1)?),
+            tag => return Err(format!("Unknown constant kind {}.", tag)),
+        };
+        Ok(constant)
+    }
+
+    fn op(&mut self) -> Result<OpCode, String> {
+        let op = match self.u8()? {
+            0 => OpCode::OpReturn,
+            1 => OpCode::OpConstant(self.u8()?),
+            2 => OpCode::OpConstantLong(self.u32()?),
+            3 => OpCode::OpDefineGlobal(self.u8()?),
+            4 => OpCode::OpDefineGlobalLong(self.u32()?),
+            5 => OpCode::OpGetGlobal(self.u8()?),
+            6 => OpCode::OpGetGlobalLong(self.u32()?),
+            7 => OpCode::OpSetGlobal(self.u8()?),
+            8 => OpCode::OpSetGlobalLong(self.u32()?),
+            9 => OpCode::OpGetLocal(self.u8()?),
+            10 => OpCode::OpSetLocal(self.u8()?),
+            11 => OpCode::OpNil,
+            12 => OpCode::OpTrue,
+            13 => OpCode::OpFalse,
+            14 => OpCode::OpNegate,
+            15 => OpCode::OpAdd,
+            16 => OpCode::OpSubtract,
+            17 => OpCode::OpMultiply,
+            18 => OpCode::OpDivide,
+            19 => OpCode::OpPow,
+            20 => OpCode::OpLeftShift,
+            21 => OpCode::OpRightShift,
+            22 => OpCode::OpNot,
+            23 => OpCode::OpEqual,
+            24 => OpCode::OpGreater,
+            25 => OpCode::OpLess,
+            26 => OpCode::OpPrint,
+            27 => OpCode::OpPop,
+            28 => OpCode::OpJumpIfFalse(self.u16()?),
+            29 => OpCode::OpJump(self.u16()?),
+            30 => OpCode::OpLoop(self.u16()?),
+            31 => OpCode::OpCall(self.u8()?),
+            32 => OpCode::OpClass(self.u32()?),
+            33 => OpCode::OpMethod(self.u32()?),
+            34 => OpCode::OpGetProperty(self.u32()?),
+            35 => OpCode::OpSetProperty(self.u32()?),
+            36 => OpCode::OpInvoke(self.u32()?, self.u8()?),
+            37 => OpCode::OpClosure(self.u32()?),
+            38 => OpCode::OpGetUpvalue(self.u8()?),
+            39 => OpCode::OpSetUpvalue(self.u8()?),
+            40 => OpCode::OpCloseUpvalue,
+            41 => OpCode::OpBuildList(self.u32()?),
+            42 => OpCode::OpIndexGet,
+            43 => OpCode::OpIndexSet,
+            44 => OpCode::OpBuildMap(self.u32()?),
+            45 => OpCode::OpModulo,
+            46 => OpCode::OpBitAnd,
+            47 => OpCode::OpBitOr,
+            48 => OpCode::OpBitXor,
+            49 => OpCode::OpBitNot,
+            tag => return Err(format!("Unknown instruction {}.", tag)),
+        };
+        Ok(op)
+    }
+}
+
+// Decoded procedure, checked before anything is allocated on the heap
+struct Prototype {
+    name: String,
+    arity: usize,
+    upvalues: Vec<Upvalue>,
+    code: Vec<OpCode>,
+    lines: Vec<usize>,
+    constants: Vec<Constant>,
+}
+
+enum Constant {
+    Value(Value), // Constant that doesn't live on the heap
+    String(String),
+    Function(Prototype),
+}
+
+impl Prototype {
+    // Check that the instructions only refer to constants, upvalues and
+    // instructions that exist. `enclosing_upvalues` is the number of
+    // upvalues of the procedure this one is declared in, `depth` the
+    // number of procedures it is nested in
+    fn validate(&self, enclosing_upvalues: usize, depth: usize) -> Result<(), String> {
+        if depth > MAX_NESTING {
+            return Err("Functions nested too deeply.".to_string());
+        }
+        for upvalue in self.upvalues.iter() {
+            if !upvalue.is_local && upvalue.index as usize >= enclosing_upvalues {
+                return Err(format!("Invalid upvalue in {}.", self.display_name()));
+            }
+        }
+
+        // Execution must never run past the end of the chunk
+        if !matches!(self.code.last(), Some(OpCode::OpReturn)) {
+            return Err(format!(
+                "{} doesn't end with a return.",
+                self.display_name()
+            ));
+        }
+
+        for (offset, &op) in self.code.iter().enumerate() {
+            let valid = match op {
+                OpCode::OpConstant(i) => self.has_value(i as usize),
+                OpCode::OpConstantLong(i) => self.has_value(i as usize),
+                OpCode::OpDefineGlobal(i) | OpCode::OpGetGlobal(i) | OpCode::OpSetGlobal(i) => {
+                    self.has_string(i as usize)
+                }
+                OpCode::OpDefineGlobalLong(i)
+                | OpCode::OpGetGlobalLong(i)
+                | OpCode::OpSetGlobalLong(i)
+                | OpCode::OpClass(i)
+                | OpCode::OpMethod(i)
+                | OpCode::OpGetProperty(i)
+                | OpCode::OpSetProperty(i)
+                | OpCode::OpInvoke(i, _) => self.has_string(i as usize),
+                OpCode::OpClosure(i) => {
+                    matches!(self.constants.get(i as usize), Some(Constant::Function(_)))
+                }
+                OpCode::OpGetUpvalue(i) | OpCode::OpSetUpvalue(i) => {
+                    (i as usize) < self.upvalues.len()
+                }
+                // Jumps are relative to the instruction after them
+                OpCode::OpJump(jump) | OpCode::OpJumpIfFalse(jump) => {
+                    offset + 1 + (jump as usize) < self.code.len()
+                }
+                OpCode::OpLoop(jump) => jump as usize <= offset,
+                _ => true,
+            };
+            if !valid {
+                return Err(self.invalid(offset, &format!("bad operand of {}.", op)));
+            }
+        }
+        self.validate_stack()?;
+
+        for constant in self.constants.iter() {
+            if let Constant::Function(function) = constant {
+                function.validate(self.upvalues.len(), depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Follow every path through the code, tracking how many values the frame
+    // holds: the procedure and its parameters, then locals and temporaries.
+    // No instruction may pop more values than there are, or use a local slot
+    // beyond them, and paths joining must agree on the number of values
+    fn validate_stack(&self) -> Result<(), String> {
+        let mut depths: Vec<Option<usize>> = vec![None; self.code.len()];
+        let mut pending = vec![(0, self.arity + 1)];
+        while let Some((offset, depth)) = pending.pop() {
+            match depths[offset] {
+                Some(known) if known == depth => continue,
+                Some(_) => return Err(self.invalid(offset, "inconsistent stack depth.")),
+                None => depths[offset] = Some(depth),
+            }
+
+            let op = self.code[offset];
+            let (pops, pushes) = stack_effect(op);
+            if depth < pops {
+                return Err(self.invalid(offset, &format!("{} underflows the stack.", op)));
+            }
+            let valid = match op {
+                OpCode::OpGetLocal(slot) | OpCode::OpSetLocal(slot) => (slot as usize) < depth,
+                // Captured locals must exist when the closure is created
+                OpCode::OpClosure(i) => match &self.constants[i as usize] {
+                    Constant::Function(function) => function
+                        .upvalues
+                        .iter()
+                        .all(|upvalue| !upvalue.is_local || (upvalue.index as usize) < depth),
+                    _ => true,
+                },
+                _ => true,
+            };
+            if !valid {
+                return Err(self.invalid(offset, &format!("bad local slot of {}.", op)));
+            }
+
+            // Jump targets were checked to lie inside the chunk
+            let depth = depth - pops + pushes;
+            match op {
+                OpCode::OpReturn => (),
+                OpCode::OpJump(jump) => pending.push((offset + 1 + jump as usize, depth)),
+                OpCode::OpJumpIfFalse(jump) => {
+                    pending.push((offset + 1 + jump as usize, depth));
+                    pending.push((offset + 1, depth));
+                }
+                OpCode::OpLoop(jump) => pending.push((offset - jump as usize, depth)),
+                _ => pending.push((offset + 1, depth)),
+            }
+        }
+        Ok(())
+    }
+
+    fn invalid(&self, offset: usize, msg: &str) -> String {
+        format!(
+            "Invalid instruction {:04} in {}: {}",
+            offset,
+            self.display_name(),
+            msg
+        )
+    }
+
+    fn has_value(&self, index: usize) -> bool {
+        matches!(
+            self.constants.get(index),
+            Some(Constant::Value(_) | Constant::String(_))
+        )
+    }
+
+    fn has_string(&self, index: usize) -> bool {
+        matches!(self.constants.get(index), Some(Constant::String(_)))
+    }
+
+    fn display_name(&self) -> String {
+        if self.name.is_empty() {
+            "<script>".to_string()
+        } else {
+            format!("<brick {}>", self.name)
+        }
+    }
+
+    // Move the procedure onto the heap. Its constants are kept on the
+    // VM stack until the function holding them has been allocated.
+    // `validate` already rejected procedures nested too deeply
+    fn allocate(self, vm: &mut VM, depth: usize) -> ObjRef {
+        assert!(depth <= MAX_NESTING, "Functions nested too deeply.");
+        let mut function = ObjFunction::new(self.name);
+        function.arity = self.arity;
+        function.upvalues = self.upvalues;
+        function.chunk = Chunk::new();
+        for (op, line) in self.code.into_iter().zip(self.lines) {
+            function.chunk.write_chunk(op, line);
+        }
+
+        let count = self.constants.len();
+        for constant in self.constants {
+            let value = match constant {
+                Constant::Value(value) => value,
+                Constant::String(chars) => Value::Object(vm.intern(chars)),
+                Constant::Function(function) => Value::Object(function.allocate(vm, depth + 1)),
+            };
+            vm.push(value);
+            // Written directly, the indices in the code must stay as they are
+            function.chunk.constants.write_value_array(value);
+        }
+
+        let function = vm.alloc(Obj::Function(function));
+        for _ in 0..count {
+            vm.pop();
+        }
+        function
+    }
+}
+
+// Number of values `op` pops off the stack and pushes onto it. Instructions
+// that only look at the top value count as popping and pushing it again
+fn stack_effect(op: OpCode) -> (usize, usize) {
+    match op {
+        OpCode::OpConstant(_)
+        | OpCode::OpConstantLong(_)
+        | OpCode::OpGetGlobal(_)
+        | OpCode::OpGetGlobalLong(_)
+        | OpCode::OpGetLocal(_)
+        | OpCode::OpGetUpvalue(_)
+        | OpCode::OpNil
+        | OpCode::OpTrue
+        | OpCode::OpFalse
+        | OpCode::OpClass(_)
+        | OpCode::OpClosure(_) => (0, 1),
+        OpCode::OpReturn
+        | OpCode::OpDefineGlobal(_)
+        | OpCode::OpDefineGlobalLong(_)
+        | OpCode::OpPrint
+        | OpCode::OpPop
+        | OpCode::OpCloseUpvalue => (1, 0),
+        OpCode::OpSetGlobal(_)
+        | OpCode::OpSetGlobalLong(_)
+        | OpCode::OpSetLocal(_)
+        | OpCode::OpSetUpvalue(_)
+        | OpCode::OpJumpIfFalse(_)
+        | OpCode::OpNegate
+        | OpCode::OpNot
+        | OpCode::OpBitNot
+        | OpCode::OpGetProperty(_) => (1, 1),
+        OpCode::OpAdd
+        | OpCode::OpSubtract
+        | OpCode::OpMultiply
+        | OpCode::OpDivide
+        | OpCode::OpPow
+        | OpCode::OpLeftShift
+        | OpCode::OpRightShift
+        | OpCode::OpModulo
+        | OpCode::OpBitAnd
+        | OpCode::OpBitOr
+        | OpCode::OpBitXor
+        | OpCode::OpEqual
+        | OpCode::OpGreater
+        | OpCode::OpLess
+        | OpCode::OpSetProperty(_)
+        | OpCode::OpIndexGet => (2, 1),
+        // The method is popped, its class stays below it
+        OpCode::OpMethod(_) => (2, 1),
+        OpCode::OpIndexSet => (3, 1),
+        OpCode::OpJump(_) | OpCode::OpLoop(_) => (0, 0),
+        // The callee and its arguments are replaced by the result
+        OpCode::OpCall(arg_count) | OpCode::OpInvoke(_, arg_count) => (arg_count as usize + 1, 1),
+        OpCode::OpBuildList(count) => (count as usize, 1),
+        OpCode::OpBuildMap(count) => (2 * count as usize, 1),
+    }
+}
+
+// ### TESTS ###
+
+#[cfg(test)]
+mod tests {
+
+    use crate::bytecode::{deserialize, serialize, MAGIC, MAX_NESTING, VERSION};
+    use crate::chunk::OpCode;
+    use crate::error::VeraError;
+    use crate::memory::Heap;
+    use crate::object::{Obj, ObjFunction, ObjString, Upvalue};
+    use crate::value::Value;
+    use crate::vm::VM;
+
+    const SCRIPT: &str = "brick counter() {
+        pink count = 0;
+        brick increment() { count = count + 1; goodbye count; }
+        goodbye increment;
+    }
+    pink next = counter();
+    next();
+    shine next() + 0.5;";
+
+    fn compile(source: &str) -> Vec<u8> {
+        VM::new().compile_to_bytes(source).expect("Script compiles")
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = compile(SCRIPT);
+        assert!(bytes.starts_with(MAGIC));
+
+        // Loading and saving again gives the same file
+        let mut vm = VM::new();
+        let function = deserialize(&bytes, &mut vm).expect("Byte code loads");
+        assert_eq!(serialize(function, vm.heap()), bytes);
+    }
+
+    #[test]
+    fn test_rejects_invalid_files() {
+        let bytes = compile(SCRIPT);
+        let load = |bytes: &[u8]| deserialize(bytes, &mut VM::new()).unwrap_err();
+
+        assert_eq!(load(b"pink a = 1;"), "Not a Vera byte code file.");
+
+        let mut version = bytes.clone();
+        version[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(load(&version).starts_with("Unsupported byte code version"));
+
+        // Every truncation is an error, never a panic
+        for len in 0..bytes.len() {
+            load(&bytes[..len]);
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(load(&trailing), "Unexpected data after the script.");
+    }
+
+    #[test]
+    fn test_rejects_jumps_out_of_the_chunk() {
+        let mut heap = Heap::new();
+        let mut function = ObjFunction::new(String::new());
+        function.chunk.write_chunk(OpCode::OpJump(2), 1);
+        function.chunk.write_chunk(OpCode::OpNil, 1);
+        function.chunk.write_chunk(OpCode::OpReturn, 1);
+        let function = heap.alloc(Obj::Function(function));
+
+        let error = deserialize(&serialize(function, &heap), &mut VM::new()).unwrap_err();
+        assert_eq!(
+            error,
+            "Invalid instruction 0000 in <script>: bad operand of OpJump 2."
+        );
+    }
+
+    // Load a script made of `code`, returning the error
+    fn load_code(code: &[OpCode]) -> String {
+        let mut heap = Heap::new();
+        let mut function = ObjFunction::new(String::new());
+        for &op in code {
+            function.chunk.write_chunk(op, 1);
+        }
+        let function = heap.alloc(Obj::Function(function));
+        deserialize(&serialize(function, &heap), &mut VM::new()).unwrap_err()
+    }
+
+    #[test]
+    fn test_rejects_locals_outside_the_frame() {
+        let error = load_code(&[OpCode::OpGetLocal(50), OpCode::OpReturn]);
+        assert_eq!(
+            error,
+            "Invalid instruction 0000 in <script>: bad local slot of OpGetLocal 50."
+        );
+    }
+
+    #[test]
+    fn test_rejects_stack_underflow() {
+        // The first pop removes the script itself from its slot
+        let error = load_code(&[
+            OpCode::OpPop,
+            OpCode::OpPop,
+            OpCode::OpNil,
+            OpCode::OpReturn,
+        ]);
+        assert_eq!(
+            error,
+            "Invalid instruction 0001 in <script>: OpPop underflows the stack."
+        );
+
+        // Paths meeting with a different number of values
+        let error = load_code(&[
+            OpCode::OpTrue,
+            OpCode::OpJumpIfFalse(1),
+            OpCode::OpNil,
+            OpCode::OpReturn,
+        ]);
+        assert_eq!(
+            error,
+            "Invalid instruction 0003 in <script>: inconsistent stack depth."
+        );
+    }
+
+    #[test]
+    fn test_rejects_script_upvalues() {
+        let mut heap = Heap::new();
+        let mut function = ObjFunction::new(String::new());
+        function.upvalues.push(Upvalue {
+            index: 0,
+            is_local: true,
+        });
+        function.chunk.write_chunk(OpCode::OpGetUpvalue(0), 1);
+        function.chunk.write_chunk(OpCode::OpReturn, 1);
+        let function = heap.alloc(Obj::Function(function));
+
+        let error = deserialize(&serialize(function, &heap), &mut VM::new()).unwrap_err();
+        assert_eq!(error, "The script can't have upvalues.");
+    }
+
+    #[test]
+    fn test_rejects_deep_nesting() {
+        // Every procedure declares the next one
+        let mut heap = Heap::new();
+        let mut inner = None;
+        for _ in 0..MAX_NESTING + 2 {
+            let mut function = ObjFunction::new("nested".to_string());
+            if let Some(inner) = inner {
+                function.chunk.add_constant(Value::Object(inner));
+            }
+            function.chunk.write_chunk(OpCode::OpNil, 1);
+            function.chunk.write_chunk(OpCode::OpReturn, 1);
+            inner = Some(heap.alloc(Obj::Function(function)));
+        }
+
+        let bytes = serialize(inner.expect("Procedures were created"), &heap);
+        let error = deserialize(&bytes, &mut VM::new()).unwrap_err();
+        assert_eq!(error, "Functions nested too deeply.");
+    }
+
+    #[test]
+    fn test_method_errors() {
+        // Run a script adding a method called `m` to whatever `code` pushes
+        let run = |code: &[OpCode]| {
+            let mut heap = Heap::new();
+            let name = heap.alloc(Obj::String(ObjString {
+                chars: "m".to_string(),
+            }));
+            let mut function = ObjFunction::new(String::new());
+            function.chunk.add_constant(Value::Object(name));
+            for &op in code {
+                function.chunk.write_chunk(op, 1);
+            }
+            function.chunk.write_chunk(OpCode::OpMethod(0), 1);
+            function.chunk.write_chunk(OpCode::OpReturn, 1);
+            let function = heap.alloc(Obj::Function(function));
+
+            match VM::new().interpret_bytes(&serialize(function, &heap)) {
+                Err(VeraError::Runtime(error)) => error.message,
+                _ => panic!("Expected a runtime error"),
+            }
+        };
+
+        assert_eq!(
+            run(&[OpCode::OpNil, OpCode::OpNil]),
+            "Methods can only be added to classes."
+        );
+        assert_eq!(
+            run(&[OpCode::OpClass(0), OpCode::OpNil]),
+            "Methods must be bricks."
+        );
+    }
+}
@@ -2,15 +2,28 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file.
 
-// Byte code instructions
+use std::collections::HashMap;
+
+use crate::memory::ObjRef;
+
+// Maximum number of constants in one chunk, the widest
+// constant operand is 24 bits
+pub const CONSTANTS_MAX: usize = 1 << 24;
+
+// Byte code instructions. Constants and globals have a short form with a
+// one byte operand and a long form used once a chunk has more constants
 #[derive(Copy, Clone)]
 #[repr(u8)]
 pub enum OpCode {
     OpReturn,
     OpConstant(u8),
+    OpConstantLong(u32),
     OpDefineGlobal(u8),
+    OpDefineGlobalLong(u32),
     OpGetGlobal(u8),
+    OpGetGlobalLong(u32),
     OpSetGlobal(u8),
+    OpSetGlobalLong(u32),
     OpGetLocal(u8),
     OpSetLocal(u8),
     OpNil,
@@ -24,6 +37,11 @@ pub enum OpCode {
     OpPow,
     OpLeftShift,
     OpRightShift,
+    OpModulo,
+    OpBitAnd,
+    OpBitOr,
+    OpBitXor,
+    OpBitNot,
     OpNot,
     OpEqual,
     OpGreater,
@@ -33,6 +51,20 @@ pub enum OpCode {
     OpJumpIfFalse(u16),
     OpJump(u16),
     OpLoop(u16),
+    OpCall(u8),
+    OpClass(u32),
+    OpMethod(u32),
+    OpGetProperty(u32),
+    OpSetProperty(u32),
+    OpInvoke(u32, u8),
+    OpClosure(u32),
+    OpGetUpvalue(u8),
+    OpSetUpvalue(u8),
+    OpCloseUpvalue,
+    OpBuildList(u32),
+    OpBuildMap(u32),
+    OpIndexGet,
+    OpIndexSet,
 }
 
 use std::fmt;
@@ -41,7 +73,8 @@ impl fmt::Display for OpCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             OpCode::OpReturn => write!(f, "OpReturn"),
-            OpCode::OpConstant(_) => write!(f, "OpConstant"),
+            OpCode::OpConstant(v) => write!(f, "OpConstant {}", v),
+            OpCode::OpConstantLong(v) => write!(f, "OpConstantLong {}", v),
             OpCode::OpAdd => write!(f, "OpAdd"),
             OpCode::OpSubtract => write!(f, "OpSubtract"),
             OpCode::OpMultiply => write!(f, "OpMultiply"),
@@ -50,6 +83,11 @@ impl fmt::Display for OpCode {
             OpCode::OpPow => write!(f, "OpPow"),
             OpCode::OpRightShift => write!(f, "OpRightShift"),
             OpCode::OpLeftShift => write!(f, "OpLeftShift"),
+            OpCode::OpModulo => write!(f, "OpModulo"),
+            OpCode::OpBitAnd => write!(f, "OpBitAnd"),
+            OpCode::OpBitOr => write!(f, "OpBitOr"),
+            OpCode::OpBitXor => write!(f, "OpBitXor"),
+            OpCode::OpBitNot => write!(f, "OpBitNot"),
             OpCode::OpTrue => write!(f, "OpTrue"),
             OpCode::OpFalse => write!(f, "OpFalse"),
             OpCode::OpNil => write!(f, "OpNil"),
@@ -60,27 +98,66 @@ impl fmt::Display for OpCode {
             OpCode::OpPrint => write!(f, "OpPrint"),
             OpCode::OpPop => write!(f, "OpPop"),
             OpCode::OpDefineGlobal(v) => write!(f, "OpDefineGlobal {}", v),
+            OpCode::OpDefineGlobalLong(v) => write!(f, "OpDefineGlobalLong {}", v),
             OpCode::OpGetGlobal(v) => write!(f, "OpGetGlobal {}", v),
+            OpCode::OpGetGlobalLong(v) => write!(f, "OpGetGlobalLong {}", v),
             OpCode::OpSetGlobal(v) => write!(f, "OpSetGlobal {}", v),
+            OpCode::OpSetGlobalLong(v) => write!(f, "OpSetGlobalLong {}", v),
             OpCode::OpGetLocal(v) => write!(f, "OpGetLocal {}", v),
             OpCode::OpSetLocal(v) => write!(f, "OpSetLocal {}", v),
             OpCode::OpJumpIfFalse(v) => write!(f, "OpJumpIfFalse {}", v),
             OpCode::OpJump(v) => write!(f, "OpJump {}", v),
             OpCode::OpLoop(v) => write!(f, "OpLoop {}", v),
+            OpCode::OpCall(v) => write!(f, "OpCall {}", v),
+            OpCode::OpClass(v) => write!(f, "OpClass {}", v),
+            OpCode::OpMethod(v) => write!(f, "OpMethod {}", v),
+            OpCode::OpGetProperty(v) => write!(f, "OpGetProperty {}", v),
+            OpCode::OpSetProperty(v) => write!(f, "OpSetProperty {}", v),
+            OpCode::OpInvoke(v, c) => write!(f, "OpInvoke {} {}", v, c),
+            OpCode::OpClosure(v) => write!(f, "OpClosure {}", v),
+            OpCode::OpGetUpvalue(v) => write!(f, "OpGetUpvalue {}", v),
+            OpCode::OpSetUpvalue(v) => write!(f, "OpSetUpvalue {}", v),
+            OpCode::OpCloseUpvalue => write!(f, "OpCloseUpvalue"),
+            OpCode::OpBuildList(v) => write!(f, "OpBuildList {}", v),
+            OpCode::OpBuildMap(v) => write!(f, "OpBuildMap {}", v),
+            OpCode::OpIndexGet => write!(f, "OpIndexGet"),
+            OpCode::OpIndexSet => write!(f, "OpIndexSet"),
         }
     }
 }
 
-use std::usize;
-
 use crate::value::Value;
 use crate::value::ValueArray;
 
+// Hashable form of a constant, used to store every constant only once.
+// Numbers are compared by their bits, strings are interned
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Bool(bool),
+    Number(u64),
+    Int(i64),
+    Object(ObjRef),
+    Nil,
+}
+
+impl From<Value> for ConstantKey {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Bool(v) => ConstantKey::Bool(v),
+            Value::Number(v) => ConstantKey::Number(v.to_bits()),
+            Value::Int(v) => ConstantKey::Int(v),
+            Value::Object(v) => ConstantKey::Object(v),
+            Value::Nil => ConstantKey::Nil,
+        }
+    }
+}
+
 // Chunk of byte code
 pub struct Chunk {
-    pub code: Vec<OpCode>,     // Dynamic array if bytes
-    pub constants: ValueArray, // Array of Vera values
-    pub line: Vec<usize>,      // Line of each chunk in Vera source code
+    pub code: Vec<OpCode>,                         // Dynamic array if bytes
+    pub constants: ValueArray,                     // Array of Vera values
+    pub line: Vec<usize>,                          // Line of each chunk in Vera source code
+    constant_indices: HashMap<ConstantKey, usize>, // Index of every constant in `constants`
 }
 
 impl Clone for Chunk {
@@ -89,17 +166,25 @@ impl Clone for Chunk {
             code: self.code.clone(),
             constants: self.constants.clone(),
             line: self.line.clone(),
+            constant_indices: self.constant_indices.clone(),
         }
     }
 }
 
-impl<'c> Chunk {
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chunk {
     // Create a new Chunk
     pub fn new() -> Chunk {
         Chunk {
             code: Vec::new(),
             constants: ValueArray { array: Vec::new() },
             line: Vec::new(),
+            constant_indices: HashMap::new(),
         }
     }
     // Append a byte to the chunk
@@ -112,23 +197,45 @@ impl<'c> Chunk {
     pub fn free_chunk(&mut self) -> () {
         self.code.clear();
         self.constants.free_value_array();
+        self.constant_indices.clear();
     }
-    // Add a constant value to chunk
+    // Add a constant value to chunk, returning the index of
+    // the existing constant if the value was added before
     pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::from(value);
+        if let Some(&index) = self.constant_indices.get(&key) {
+            return index;
+        }
+
         self.constants.write_value_array(value);
-        let result = self.constants.array.len() - 1;
-        result
+        let index = self.constants.array.len() - 1;
+        self.constant_indices.insert(key, index);
+        index
     }
 
-    pub fn read_constant(&self, index: u8) -> Value {
-        self.constants.array[index as usize].clone()
+    pub fn read_constant(&self, index: usize) -> Value {
+        self.constants.array[index]
     }
+}
 
-    pub fn read_string(&self, index: u8) -> String {
-        if let Value::Object(s) = self.read_constant(index) {
-            s.chars
-        } else {
-            panic!("Constant is not String!");
-        }
+// ### TESTS ###
+
+#[cfg(test)]
+mod tests {
+
+    use crate::chunk::Chunk;
+    use crate::value::Value;
+
+    #[test]
+    fn test_add_constant_reuses_equal_constants() {
+        let mut chunk = Chunk::new();
+
+        assert_eq!(chunk.add_constant(Value::Number(1.0)), 0);
+        assert_eq!(chunk.add_constant(Value::Bool(true)), 1);
+        assert_eq!(chunk.add_constant(Value::Number(1.0)), 0);
+        assert_eq!(chunk.add_constant(Value::Number(-0.0)), 2);
+        assert_eq!(chunk.add_constant(Value::Number(0.0)), 3);
+        assert_eq!(chunk.add_constant(Value::Int(1)), 4);
+        assert_eq!(chunk.constants.array.len(), 5);
     }
 }
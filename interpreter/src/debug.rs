@@ -0,0 +1,146 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+//
+// This file contains the disassembler, which prints the byte code of a chunk
+// in a human readable form to debug what the parser emits.
+
+use std::fmt;
+
+use crate::chunk::{Chunk, OpCode};
+use crate::memory::Heap;
+use crate::value::Value;
+
+impl Chunk {
+    /// Prints every instruction of the chunk under a `name` header.
+    pub fn disassemble(&self, name: &str, heap: &Heap) -> () {
+        println!("== {} ==", name);
+        for offset in 0..self.code.len() {
+            println!("{}", self.disassemble_instruction(offset, heap));
+        }
+    }
+
+    /// Formats the instruction at `offset` with its source line, operands,
+    /// the constants they refer to and the targets of jumps.
+    pub fn disassemble_instruction(&self, offset: usize, heap: &Heap) -> String {
+        // Instructions on the same line as the previous one show a `|`
+        let line = if offset > 0 && self.line[offset] == self.line[offset - 1] {
+            "   |".to_string()
+        } else {
+            format!("{:4}", self.line[offset])
+        };
+        self.format_instruction(offset, line, heap)
+    }
+
+    /// Formats the instruction at `offset` like `disassemble_instruction`, but
+    /// always with its line, since execution jumps around the chunk.
+    pub fn trace_instruction(&self, offset: usize, heap: &Heap) -> String {
+        let line = format!("{:4}", self.line[offset]);
+        self.format_instruction(offset, line, heap)
+    }
+
+    fn format_instruction(&self, offset: usize, line: String, heap: &Heap) -> String {
+        let instruction = match self.code[offset] {
+            OpCode::OpConstant(i) => self.constant("OpConstant", i as usize, heap),
+            OpCode::OpConstantLong(i) => self.constant("OpConstantLong", i as usize, heap),
+            OpCode::OpDefineGlobal(i) => self.constant("OpDefineGlobal", i as usize, heap),
+            OpCode::OpDefineGlobalLong(i) => self.constant("OpDefineGlobalLong", i as usize, heap),
+            OpCode::OpGetGlobal(i) => self.constant("OpGetGlobal", i as usize, heap),
+            OpCode::OpGetGlobalLong(i) => self.constant("OpGetGlobalLong", i as usize, heap),
+            OpCode::OpSetGlobal(i) => self.constant("OpSetGlobal", i as usize, heap),
+            OpCode::OpSetGlobalLong(i) => self.constant("OpSetGlobalLong", i as usize, heap),
+            OpCode::OpClass(i) => self.constant("OpClass", i as usize, heap),
+            OpCode::OpMethod(i) => self.constant("OpMethod", i as usize, heap),
+            OpCode::OpGetProperty(i) => self.constant("OpGetProperty", i as usize, heap),
+            OpCode::OpSetProperty(i) => self.constant("OpSetProperty", i as usize, heap),
+            OpCode::OpInvoke(i, arg_count) => format!(
+                "{} ({} args)",
+                self.constant("OpInvoke", i as usize, heap),
+                arg_count
+            ),
+            OpCode::OpClosure(i) => self.closure(i as usize, heap),
+            OpCode::OpGetLocal(slot) => operand("OpGetLocal", slot),
+            OpCode::OpSetLocal(slot) => operand("OpSetLocal", slot),
+            OpCode::OpGetUpvalue(slot) => operand("OpGetUpvalue", slot),
+            OpCode::OpSetUpvalue(slot) => operand("OpSetUpvalue", slot),
+            OpCode::OpCall(arg_count) => operand("OpCall", arg_count),
+            OpCode::OpBuildList(count) => operand("OpBuildList", count),
+            OpCode::OpBuildMap(count) => operand("OpBuildMap", count),
+            // Jumps are relative to the instruction after them
+            OpCode::OpJump(jump) => jump_to("OpJump", jump, offset + 1 + jump as usize),
+            OpCode::OpJumpIfFalse(jump) => {
+                jump_to("OpJumpIfFalse", jump, offset + 1 + jump as usize)
+            }
+            OpCode::OpLoop(jump) => jump_to("OpLoop", jump, offset - jump as usize),
+            simple => simple.to_string(),
+        };
+
+        format!("{:04} {} {}", offset, line, instruction)
+    }
+
+    // Instruction with a constant operand, followed by the constant's value
+    fn constant(&self, name: &str, index: usize, heap: &Heap) -> String {
+        let value = self.constants.array[index];
+        format!("{:<18} {:4} '{}'", name, index, heap.format_value(value))
+    }
+
+    // `OpClosure` is followed by the variables the new closure captures
+    fn closure(&self, index: usize, heap: &Heap) -> String {
+        let mut text = self.constant("OpClosure", index, heap);
+        if let Value::Object(function) = self.constants.array[index] {
+            for upvalue in heap.as_function(function).upvalues.iter() {
+                let kind = if upvalue.is_local { "local" } else { "upvalue" };
+                text.push_str(&format!("\n        |   {:<14} {:4}", kind, upvalue.index));
+            }
+        }
+        text
+    }
+}
+
+// Instruction with a plain number operand, like a stack slot or an argument count
+fn operand(name: &str, operand: impl fmt::Display) -> String {
+    format!("{:<18} {:4}", name, operand)
+}
+
+fn jump_to(name: &str, jump: u16, target: usize) -> String {
+    format!("{:<18} {:4} -> {:04}", name, jump, target)
+}
+
+// ### TESTS ###
+
+#[cfg(test)]
+mod tests {
+
+    use crate::chunk::{Chunk, OpCode};
+    use crate::memory::Heap;
+    use crate::value::Value;
+
+    #[test]
+    fn test_disassemble_instruction() {
+        let heap = Heap::new();
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Value::Number(1.5)) as u8;
+        chunk.write_chunk(OpCode::OpConstant(constant), 1);
+        chunk.write_chunk(OpCode::OpJumpIfFalse(2), 1);
+        chunk.write_chunk(OpCode::OpPrint, 2);
+        chunk.write_chunk(OpCode::OpLoop(3), 2);
+
+        assert_eq!(
+            chunk.disassemble_instruction(0, &heap),
+            "0000    1 OpConstant            0 '1.5'"
+        );
+        assert_eq!(
+            chunk.disassemble_instruction(1, &heap),
+            "0001    | OpJumpIfFalse         2 -> 0004"
+        );
+        assert_eq!(chunk.disassemble_instruction(2, &heap), "0002    2 OpPrint");
+        assert_eq!(
+            chunk.disassemble_instruction(3, &heap),
+            "0003    | OpLoop                3 -> 0000"
+        );
+        assert_eq!(
+            chunk.trace_instruction(3, &heap),
+            "0003    2 OpLoop                3 -> 0000"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+//
+// This file contains the errors reported while compiling and running Vera code.
+
+use std::fmt;
+
+/// Error raised while executing a script.
+/// - `message`: What went wrong.
+/// - `line`: Source line of the instruction that failed.
+/// - `trace`: The procedure calls active when the error occurred, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+    pub trace: Vec<TraceFrame>,
+}
+
+/// A single procedure call of a `RuntimeError` stack trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub line: usize,      // Line being executed in the procedure
+    pub function: String, // Name of the procedure, empty for the top level script
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.function.is_empty() {
+            write!(f, "[line {}] in script", self.line)
+        } else {
+            write!(f, "[line {}] in brick {}", self.line, self.function)
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in self.trace.iter() {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl RuntimeError {
+    // Error of an embedding call made while no code runs, so it has no trace
+    pub(crate) fn untraced(message: String) -> RuntimeError {
+        RuntimeError {
+            message,
+            line: 0,
+            trace: Vec::new(),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Error found while compiling a script.
+/// - `line`, `column`: Position of the token the error was found at.
+/// - `lexeme`: Source text of that token, `None` at the end of the script
+///   and empty for text the lexer couldn't turn into a token.
+/// - `message`: What went wrong.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    pub column: usize,
+    pub lexeme: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}:{}] Error", self.line, self.column)?;
+        match &self.lexeme {
+            None => write!(f, " at end")?,
+            Some(lexeme) if lexeme.is_empty() => (),
+            Some(lexeme) => write!(f, " at '{}'", lexeme)?,
+        };
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// Exit code of a script that failed to compile
+pub const EXIT_COMPILE_ERROR: i32 = 65;
+// Exit code of a script that failed while running
+pub const EXIT_RUNTIME_ERROR: i32 = 70;
+
+/// Error returned by `VM::interpret`, either every error found while
+/// compiling the script or the error that stopped its execution.
+/// `Load` is a byte code file that is corrupted or of another version.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VeraError {
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+    Load(String),
+}
+
+impl VeraError {
+    // Conventional process exit code for the error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            VeraError::Compile(_) | VeraError::Load(_) => EXIT_COMPILE_ERROR,
+            VeraError::Runtime(_) => EXIT_RUNTIME_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for VeraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VeraError::Compile(errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", errors.join("\n"))
+            }
+            VeraError::Runtime(error) => write!(f, "{}", error),
+            VeraError::Load(message) => write!(f, "Unable to load byte code: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for VeraError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VeraError::Compile(errors) => errors
+                .first()
+                .map(|e| e as &(dyn std::error::Error + 'static)),
+            VeraError::Runtime(error) => Some(error),
+            VeraError::Load(_) => None,
+        }
+    }
+}
+
+impl From<RuntimeError> for VeraError {
+    fn from(error: RuntimeError) -> Self {
+        VeraError::Runtime(error)
+    }
+}
@@ -14,7 +14,10 @@ pub enum TokenType {
     TokenLeftParen,
     TokenLeftBrace,
     TokenRightBrace,
+    TokenLeftBracket,
+    TokenRightBracket,
     TokenComma,
+    TokenColon,
     TokenDot,
     TokenMinus,
     TokenPlus,
@@ -22,6 +25,10 @@ pub enum TokenType {
     TokenSlash,
     TokenStar,
     TokenPow,
+    TokenPercent,
+    TokenAmpersand,
+    TokenPipe,
+    TokenTilde,
     // One or two character tokens.
     TokenBang,
     TokenBangEqual,
@@ -35,6 +42,11 @@ pub enum TokenType {
     TokenMinusMinus,
     TokenShiftRigth,
     TokenShiftLeft,
+    TokenPlusEqual,
+    TokenMinusEqual,
+    TokenStarEqual,
+    TokenSlashEqual,
+    TokenPowEqual,
     // Literals.
     TokenIdentifier,
     TokenString,
@@ -51,6 +63,7 @@ pub enum TokenType {
     TokenOr,
     TokenPrint,
     TokenReturn,
+    TokenThis,
     TokenTrue,
     TokenVar,
     TokenWhile,
@@ -59,6 +72,25 @@ pub enum TokenType {
     TokenEOF,
 }
 
+/// Every reserved word of Vera and the token it is scanned as.
+pub const KEYWORDS: [(&str, TokenType); 15] = [
+    ("and", TokenType::TokenAnd),
+    ("class", TokenType::TokenClass),
+    ("anybody", TokenType::TokenElse),
+    ("false", TokenType::TokenFalse),
+    ("time", TokenType::TokenFor),
+    ("brick", TokenType::TokenFun),
+    ("outThere?", TokenType::TokenIf),
+    ("money", TokenType::TokenNil),
+    ("or", TokenType::TokenOr),
+    ("shine", TokenType::TokenPrint),
+    ("goodbye", TokenType::TokenReturn),
+    ("this", TokenType::TokenThis),
+    ("true", TokenType::TokenTrue),
+    ("pink", TokenType::TokenVar),
+    ("echoes", TokenType::TokenWhile),
+];
+
 impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -70,6 +102,18 @@ impl fmt::Display for TokenType {
             TokenType::TokenMinusMinus => write!(f, "Token Minus Minus"),
             TokenType::TokenPlusPlus => write!(f, "Token Plus Plus"),
             TokenType::TokenPow => write!(f, "Token Pow"),
+            TokenType::TokenPlusEqual => write!(f, "Token Plus Equal"),
+            TokenType::TokenMinusEqual => write!(f, "Token Minus Equal"),
+            TokenType::TokenStarEqual => write!(f, "Token Star Equal"),
+            TokenType::TokenSlashEqual => write!(f, "Token Slash Equal"),
+            TokenType::TokenPowEqual => write!(f, "Token Pow Equal"),
+            TokenType::TokenPercent => write!(f, "Token Percent"),
+            TokenType::TokenAmpersand => write!(f, "Token Ampersand"),
+            TokenType::TokenPipe => write!(f, "Token Pipe"),
+            TokenType::TokenTilde => write!(f, "Token Tilde"),
+            TokenType::TokenLeftBracket => write!(f, "Token LeftBracket"),
+            TokenType::TokenColon => write!(f, "Token Colon"),
+            TokenType::TokenRightBracket => write!(f, "Token RightBracket"),
             TokenType::TokenBang => write!(f, "Token Bang"),
             TokenType::TokenTrue => write!(f, "Token True"),
             TokenType::TokenFalse => write!(f, "Token False"),
@@ -97,6 +141,8 @@ impl fmt::Display for TokenType {
             TokenType::TokenPrint => write!(f, "Token Print"),
             TokenType::TokenIf => write!(f, "Token If"),
             TokenType::TokenElse => write!(f, "Token Else"),
+            TokenType::TokenClass => write!(f, "Token Class"),
+            TokenType::TokenThis => write!(f, "Token This"),
             _ => todo!(),
         }
     }
@@ -118,7 +164,7 @@ impl Token {
             _type,
             lexeme: lexeme.to_string(),
             line: scanner.line,
-            col: scanner.col - lexeme.len(),
+            col: scanner.col - lexeme.chars().count(),
         }
     }
 
@@ -127,7 +173,7 @@ impl Token {
             _type,
             lexeme: lexeme.to_string(),
             line: scanner.line,
-            col: scanner.col - lexeme.len(),
+            col: scanner.col - lexeme.chars().count(),
         }
     }
 
@@ -157,8 +203,8 @@ impl Clone for Token {
 pub struct Scanner<'s> {
     source: &'s str,    // Source string to be scanned
     tokens: Vec<Token>, // Vector holding generated tokens
-    start: usize,       // Start of current lexeme
-    current: usize,     // Index of current character
+    start: usize,       // Byte index of the start of the current lexeme
+    current: usize,     // Byte index of the current character
     line: usize,        // Current line in source string
     col: usize,         // Current column in source string
 }
@@ -199,6 +245,10 @@ impl<'s> Scanner<'s> {
     fn scan_token(&mut self) -> () {
         // Remove all white space
         self.skip_whitespace();
+        // Trailing white space is not a token
+        if self.is_at_end() {
+            return;
+        }
         // Set start of current lexeme
         self.start = self.current;
 
@@ -219,24 +269,68 @@ impl<'s> Scanner<'s> {
                 .tokens
                 .push(Token::new(TokenType::TokenSemicolon, self)),
             '.' => self.tokens.push(Token::new(TokenType::TokenDot, self)),
-            '^' => self.tokens.push(Token::new(TokenType::TokenPow, self)),
+            ':' => self.tokens.push(Token::new(TokenType::TokenColon, self)),
+            '^' => {
+                // Check if its a two character token
+                let res = if self.match_next('=') {
+                    TokenType::TokenPowEqual
+                } else {
+                    TokenType::TokenPow
+                };
+
+                self.tokens.push(Token::new(res, self));
+            }
+            '%' => self.tokens.push(Token::new(TokenType::TokenPercent, self)),
+            '&' => self
+                .tokens
+                .push(Token::new(TokenType::TokenAmpersand, self)),
+            '|' => self.tokens.push(Token::new(TokenType::TokenPipe, self)),
+            '~' => self.tokens.push(Token::new(TokenType::TokenTilde, self)),
+            '[' => self
+                .tokens
+                .push(Token::new(TokenType::TokenLeftBracket, self)),
+            ']' => self
+                .tokens
+                .push(Token::new(TokenType::TokenRightBracket, self)),
             '-' => {
                 // Check if its a two character token
                 let res = if self.match_next('-') {
                     TokenType::TokenMinusMinus
+                } else if self.match_next('=') {
+                    TokenType::TokenMinusEqual
                 } else {
                     TokenType::TokenMinus
                 };
 
                 self.tokens.push(Token::new(res, self));
             }
-            '*' => self.tokens.push(Token::new(TokenType::TokenStar, self)),
+            '*' => {
+                // Check if its a two character token
+                let res = if self.match_next('=') {
+                    TokenType::TokenStarEqual
+                } else {
+                    TokenType::TokenStar
+                };
+
+                self.tokens.push(Token::new(res, self));
+            }
             ',' => self.tokens.push(Token::new(TokenType::TokenComma, self)),
-            '/' => self.tokens.push(Token::new(TokenType::TokenSlash, self)),
+            '/' => {
+                // Check if its a two character token
+                let res = if self.match_next('=') {
+                    TokenType::TokenSlashEqual
+                } else {
+                    TokenType::TokenSlash
+                };
+
+                self.tokens.push(Token::new(res, self));
+            }
             '+' => {
                 // Check if its a two character token
                 let res = if self.match_next('+') {
                     TokenType::TokenPlusPlus
+                } else if self.match_next('=') {
+                    TokenType::TokenPlusEqual
                 } else {
                     TokenType::TokenPlus
                 };
@@ -314,6 +408,14 @@ impl<'s> Scanner<'s> {
             self.advance();
         }
 
+        // A trailing '?' belongs to keywords spelled with one, like `outThere?`
+        if self.peek() == '?' {
+            let word = format!("{}?", &self.source[self.start..self.current]);
+            if self.match_keyword(&word) != TokenType::TokenIdentifier {
+                self.advance();
+            }
+        }
+
         // Create current lexeme
         let value: &str = self.source[self.start..self.current].into();
 
@@ -329,22 +431,9 @@ impl<'s> Scanner<'s> {
     // Check if identifier is a keyword, return its type. If its not a keyword return identifier
     // type
     fn match_keyword(&self, word: &str) -> TokenType {
-        match word {
-            "and" => TokenType::TokenAnd,
-            "class" => TokenType::TokenClass,
-            "anybody" => TokenType::TokenElse,
-            "false" => TokenType::TokenFalse,
-            "time" => TokenType::TokenFor,
-            "brick" => TokenType::TokenFun,
-            "outThere" => TokenType::TokenIf,
-            "money" => TokenType::TokenNil,
-            "or" => TokenType::TokenOr,
-            "shine" => TokenType::TokenPrint,
-            "goodbye" => TokenType::TokenReturn,
-            "true" => TokenType::TokenTrue,
-            "pink" => TokenType::TokenVar,
-            "echoes" => TokenType::TokenWhile,
-            _ => TokenType::TokenIdentifier,
+        match KEYWORDS.iter().find(|(keyword, _)| *keyword == word) {
+            Some(&(_, keyword)) => keyword,
+            None => TokenType::TokenIdentifier,
         }
     }
 
@@ -396,34 +485,14 @@ impl<'s> Scanner<'s> {
 
     // Get current character. Get \0 if at the end
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-
-        return self
-            .source
-            .chars()
-            .nth(self.current)
-            .unwrap_or_else(|| panic!("Error in peek(). No character at index {}", self.current));
+        return self.source[self.current..].chars().next().unwrap_or('\0');
     }
 
     // Get next character. Get \0 if the next character is at the end
     fn peek_next(&self) -> char {
-        if self.current > self.source.len() - 2 {
-            return '\0';
-        }
-
-        return self
-            .source
-            .chars()
-            .nth(self.current + 1)
-            .unwrap_or_else(|| {
-                panic!(
-                    "Error in peek_next(). No character at index {}. Current character was {}",
-                    self.current,
-                    self.source.chars().nth(self.current).unwrap()
-                )
-            });
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        return chars.next().unwrap_or('\0');
     }
 
     // Remove all whitespace/comments characters from source string
@@ -439,12 +508,10 @@ impl<'s> Scanner<'s> {
                     self.advance();
                 }
                 '/' if self.peek_next() == '/' => {
+                    // The new line itself is handled above
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
-                    // Entered new line
-                    self.line += 1;
-                    self.col = 1;
                 }
                 _ => return,
             }
@@ -456,20 +523,21 @@ impl<'s> Scanner<'s> {
         if self.is_at_end() || self.peek() != expected {
             return false;
         } else {
-            self.current += 1;
+            self.current += expected.len_utf8();
             return true;
         }
     }
 
     // Check if scanner reached the end of source string
     fn is_at_end(&self) -> bool {
-        return self.current == self.source.len() - 1;
+        return self.current >= self.source.len();
     }
 
-    // Get current char and continue to next character
+    // Get current char and continue to next character. `current` moves
+    // by the char's length in bytes, so lexemes are sliced on char boundaries
     fn advance(&mut self) -> char {
         let char = self.peek();
-        self.current += 1;
+        self.current += char.len_utf8();
         self.col += 1;
 
         char
@@ -490,8 +558,8 @@ mod tests {
         // Initialise lexer
         let mut scanner = lexer::Scanner::new(
             "( ) { } , . - + ; \n
-                / * ^ ! != = == > >= < <= ++ -- pink  name \n
-                time brick outThere anybody goodbye   echoes money shine ",
+                / * ^ % & | ~ ! != = == > >= < <= ++ -- += -= *= /= ^= pink  name \n
+                time brick outThere? anybody goodbye   echoes money shine outThere ",
         );
         // Generate vector of TokenTypes
         let tokens = scanner.scan_tokens();
@@ -510,6 +578,10 @@ mod tests {
             TokenType::TokenSlash,
             TokenType::TokenStar,
             TokenType::TokenPow,
+            TokenType::TokenPercent,
+            TokenType::TokenAmpersand,
+            TokenType::TokenPipe,
+            TokenType::TokenTilde,
             TokenType::TokenBang,
             TokenType::TokenBangEqual,
             TokenType::TokenEqual,
@@ -520,6 +592,11 @@ mod tests {
             TokenType::TokenLessEqual,
             TokenType::TokenPlusPlus,
             TokenType::TokenMinusMinus,
+            TokenType::TokenPlusEqual,
+            TokenType::TokenMinusEqual,
+            TokenType::TokenStarEqual,
+            TokenType::TokenSlashEqual,
+            TokenType::TokenPowEqual,
             TokenType::TokenVar,
             TokenType::TokenIdentifier,
             TokenType::TokenFor,
@@ -530,6 +607,7 @@ mod tests {
             TokenType::TokenWhile,
             TokenType::TokenNil,
             TokenType::TokenPrint,
+            TokenType::TokenIdentifier,
             TokenType::TokenEOF,
         ];
 
@@ -2,9 +2,14 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file
 
+mod builtins;
+pub mod bytecode;
 pub mod chunk;
+mod debug;
+pub mod error;
 pub mod lexer;
+pub mod memory;
 mod object;
 pub mod parser;
-mod value;
+pub mod value;
 pub mod vm;
@@ -0,0 +1,440 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+//
+// This file contains the line editor of the REPL. While a line is read the
+// terminal is switched to raw mode with `stty`, so keys arrive one at a time
+// and the line is redrawn after every edit.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// Number of lines kept in the history file
+const HISTORY_MAX: usize = 1000;
+
+/// Reads lines from a terminal with cursor movement, a history
+/// persisted in `~/.vera_history` and tab completion.
+pub struct LineEditor {
+    history: Vec<String>,          // Previously entered lines, oldest first
+    history_path: Option<PathBuf>, // File the history is saved in
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        let history_path =
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".vera_history"));
+        let mut editor = LineEditor {
+            history: Vec::new(),
+            history_path,
+        };
+        editor.load_history();
+        editor
+    }
+
+    /// Reads a line after showing `prompt`. `complete` lists the words that could
+    /// complete the word before the cursor when tab is pressed.
+    /// Returns `None` once the input ends.
+    pub fn read_line<F>(&mut self, prompt: &str, complete: F) -> io::Result<Option<String>>
+    where
+        F: Fn(&str) -> Vec<String>,
+    {
+        let _raw = RawMode::enable()?;
+        let mut input = io::stdin().lock();
+        let mut output = io::stdout();
+
+        let mut line = Line::new();
+        // Index of the history entry shown, `history.len()` while editing a new line
+        let mut index = self.history.len();
+        let mut typed = String::new(); // The new line, kept while browsing the history
+
+        redraw(&mut output, prompt, &line)?;
+        loop {
+            let key = match read_key(&mut input)? {
+                Some(key) => key,
+                None if line.chars.is_empty() => return Ok(None),
+                None => break,
+            };
+
+            match key {
+                Key::Enter => break,
+                Key::Char(c) => line.insert(&c.to_string()),
+                Key::Backspace => line.backspace(),
+                Key::Delete => line.delete(),
+                Key::Left => line.cursor = line.cursor.saturating_sub(1),
+                Key::Right => line.cursor = (line.cursor + 1).min(line.chars.len()),
+                Key::Home => line.cursor = 0,
+                Key::End => line.cursor = line.chars.len(),
+                Key::KillStart => {
+                    line.chars.drain(..line.cursor);
+                    line.cursor = 0;
+                }
+                Key::KillEnd => line.chars.truncate(line.cursor),
+                Key::Up if index > 0 => {
+                    if index == self.history.len() {
+                        typed = line.text();
+                    }
+                    index -= 1;
+                    line.set(&self.history[index]);
+                }
+                Key::Down if index < self.history.len() => {
+                    index += 1;
+                    match self.history.get(index) {
+                        Some(entry) => line.set(entry),
+                        None => line.set(&typed),
+                    }
+                }
+                Key::Tab => {
+                    let prefix = line.word();
+                    let mut candidates: Vec<String> = complete(&prefix)
+                        .into_iter()
+                        .filter(|candidate| candidate.starts_with(&prefix))
+                        .collect();
+                    candidates.sort();
+                    candidates.dedup();
+
+                    // Complete as much as all candidates share, list them if that's nothing
+                    let common = common_prefix(&candidates);
+                    if common.len() > prefix.len() {
+                        line.insert(&common[prefix.len()..]);
+                    } else if candidates.len() > 1 {
+                        write!(output, "\r\n{}\r\n", candidates.join("  "))?;
+                    }
+                }
+                // Ctrl-C drops the line, Ctrl-D on an empty line ends the input
+                Key::Interrupt => {
+                    write!(output, "^C\r\n")?;
+                    line = Line::new();
+                    index = self.history.len();
+                }
+                Key::Eof if line.chars.is_empty() => {
+                    write!(output, "\r\n")?;
+                    return Ok(None);
+                }
+                Key::Eof => line.delete(),
+                _ => (),
+            }
+            redraw(&mut output, prompt, &line)?;
+        }
+
+        write!(output, "\r\n")?;
+        output.flush()?;
+        let text = line.text();
+        self.add_history(&text);
+        Ok(Some(text))
+    }
+
+    // Read the history saved by previous sessions
+    fn load_history(&mut self) -> () {
+        let path = match &self.history_path {
+            Some(path) => path,
+            None => return,
+        };
+        if let Ok(content) = fs::read_to_string(path) {
+            self.history = content.lines().map(|line| line.to_string()).collect();
+        }
+
+        // Only the most recent lines are kept
+        if self.history.len() > HISTORY_MAX {
+            self.history.drain(..self.history.len() - HISTORY_MAX);
+            let mut content = self.history.join("\n");
+            content.push('\n');
+            fs::write(path, content).ok();
+        }
+    }
+
+    // Remember a line and append it to the history file
+    fn add_history(&mut self, line: &str) -> () {
+        if line.trim().is_empty() || self.history.last().map(|last| last.as_str()) == Some(line) {
+            return;
+        }
+        self.history.push(line.to_string());
+
+        if let Some(path) = &self.history_path {
+            // Failing to save the history doesn't stop the session
+            let file = OpenOptions::new().create(true).append(true).open(path);
+            if let Ok(mut file) = file {
+                writeln!(file, "{}", line).ok();
+            }
+        }
+    }
+}
+
+// Terminal mode without line buffering and echo, the previous mode is restored when dropped
+struct RawMode {
+    saved: String, // Terminal settings as printed by `stty -g`
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let saved = stty(&["-g"])?.trim().to_string();
+        stty(&[
+            "-icanon", "-echo", "-isig", "-iexten", "-ixon", "min", "1", "time", "0",
+        ])?;
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        stty(&[&self.saved]).ok();
+    }
+}
+
+// Run `stty` on the terminal the REPL reads from
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("Unable to change the terminal mode"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Draw the line over the current one and put the cursor back in place
+fn redraw(output: &mut impl Write, prompt: &str, line: &Line) -> io::Result<()> {
+    write!(output, "\r{}{}\x1b[K", prompt, line.text())?;
+    let behind = line.chars.len() - line.cursor;
+    if behind > 0 {
+        write!(output, "\x1b[{}D", behind)?;
+    }
+    output.flush()
+}
+
+// Longest prefix shared by every word
+fn common_prefix(words: &[String]) -> String {
+    let mut prefix = match words.first() {
+        Some(word) => word.clone(),
+        None => return String::new(),
+    };
+    for word in words.iter().skip(1) {
+        while !word.starts_with(&prefix) {
+            prefix.pop();
+        }
+    }
+    prefix
+}
+
+// Line being edited
+struct Line {
+    chars: Vec<char>,
+    cursor: usize, // Index of the character the cursor is on
+}
+
+impl Line {
+    fn new() -> Line {
+        Line {
+            chars: Vec::new(),
+            cursor: 0,
+        }
+    }
+
+    fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    // Replace the text, putting the cursor at its end
+    fn set(&mut self, text: &str) -> () {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    fn insert(&mut self, text: &str) -> () {
+        for c in text.chars() {
+            self.chars.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    fn backspace(&mut self) -> () {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn delete(&mut self) -> () {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    // The identifier the cursor is at the end of
+    fn word(&self) -> String {
+        let start = self.chars[..self.cursor]
+            .iter()
+            .rposition(|&c| !(c.is_alphanumeric() || c == '_'))
+            .map_or(0, |position| position + 1);
+        self.chars[start..self.cursor].iter().collect()
+    }
+}
+
+// Keys the editor reacts to
+#[derive(Debug, PartialEq)]
+enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    KillStart, // Ctrl-U
+    KillEnd,   // Ctrl-K
+    Interrupt, // Ctrl-C
+    Eof,       // Ctrl-D
+    Ignored,   // Any key without a meaning in the editor
+}
+
+// Read the next key press, `None` once the input ends
+fn read_key(input: &mut impl Read) -> io::Result<Option<Key>> {
+    let byte = match read_byte(input)? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        127 | 8 => Key::Backspace,
+        1 => Key::Home, // Ctrl-A
+        2 => Key::Left, // Ctrl-B
+        3 => Key::Interrupt,
+        4 => Key::Eof,
+        5 => Key::End,   // Ctrl-E
+        6 => Key::Right, // Ctrl-F
+        11 => Key::KillEnd,
+        14 => Key::Down, // Ctrl-N
+        16 => Key::Up,   // Ctrl-P
+        21 => Key::KillStart,
+        0x1b => read_escape(input)?,
+        byte if byte < 0x20 => Key::Ignored,
+        byte => {
+            // The leading byte of a UTF-8 character tells how many bytes follow
+            let mut bytes = vec![byte];
+            for _ in 1..byte.leading_ones().max(1) {
+                match read_byte(input)? {
+                    Some(byte) => bytes.push(byte),
+                    None => break,
+                }
+            }
+            match std::str::from_utf8(&bytes) {
+                Ok(text) => text.chars().next().map_or(Key::Ignored, Key::Char),
+                Err(_) => Key::Ignored,
+            }
+        }
+    };
+    Ok(Some(key))
+}
+
+// Decode the escape sequence sent for arrows, home, end and delete
+fn read_escape(input: &mut impl Read) -> io::Result<Key> {
+    match read_byte(input)? {
+        Some(b'[') | Some(b'O') => (),
+        _ => return Ok(Key::Ignored),
+    }
+
+    let key = match read_byte(input)? {
+        Some(b'A') => Key::Up,
+        Some(b'B') => Key::Down,
+        Some(b'C') => Key::Right,
+        Some(b'D') => Key::Left,
+        Some(b'H') => Key::Home,
+        Some(b'F') => Key::End,
+        // Sequences like `ESC [ 3 ~` end with a tilde
+        Some(digit) if digit.is_ascii_digit() => {
+            let mut code = vec![digit];
+            loop {
+                match read_byte(input)? {
+                    Some(b'~') | None => break,
+                    Some(byte) => code.push(byte),
+                }
+            }
+            match &code[..] {
+                b"1" | b"7" => Key::Home,
+                b"3" => Key::Delete,
+                b"4" | b"8" => Key::End,
+                _ => Key::Ignored,
+            }
+        }
+        _ => Key::Ignored,
+    };
+    Ok(key)
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match input.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// ### TESTS ###
+
+#[cfg(test)]
+mod tests {
+
+    use super::{common_prefix, read_key, Key, Line};
+
+    fn keys(mut input: &[u8]) -> Vec<Key> {
+        let mut keys = Vec::new();
+        while let Some(key) = read_key(&mut input).expect("Reading from a slice works") {
+            keys.push(key);
+        }
+        keys
+    }
+
+    #[test]
+    fn test_read_key() {
+        assert_eq!(
+            keys(b"a\x1b[A\x1b[D\x1b[3~\x1bOH\x7f\t\r"),
+            vec![
+                Key::Char('a'),
+                Key::Up,
+                Key::Left,
+                Key::Delete,
+                Key::Home,
+                Key::Backspace,
+                Key::Tab,
+                Key::Enter
+            ]
+        );
+        assert_eq!(keys("é".as_bytes()), vec![Key::Char('é')]);
+        assert_eq!(keys(b"\x03\x04"), vec![Key::Interrupt, Key::Eof]);
+    }
+
+    #[test]
+    fn test_line_editing() {
+        let mut line = Line::new();
+        line.insert("shine ab;");
+        line.cursor = 8;
+        assert_eq!(line.word(), "ab");
+
+        line.backspace();
+        line.insert("xy");
+        assert_eq!(line.text(), "shine axy;");
+        assert_eq!(line.word(), "axy");
+
+        line.cursor = 0;
+        line.delete();
+        assert_eq!(line.text(), "hine axy;");
+        assert_eq!(line.word(), "");
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let words = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(common_prefix(&words(&["pink", "pinky", "pine"])), "pin");
+        assert_eq!(common_prefix(&words(&["shine"])), "shine");
+        assert_eq!(common_prefix(&words(&[])), "");
+    }
+}
@@ -2,78 +2,146 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file.
 
-use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::io;
-use std::io::prelude::*;
+use std::path::Path;
 use std::process;
 
-use pf::chunk::Chunk;
+use pf::bytecode;
+use pf::memory::Heap;
 use pf::vm::VM;
 
+mod line_editor;
+mod repl;
+use repl::Repl;
+
+const USAGE: &str =
+    "Usage: pf [--gc-stress] [--gc-threshold <bytes>] [--disassemble] [--trace] [path]
+       pf [options] run <file.vbc>
+       pf compile <path> [-o <file.vbc>]";
+
 fn main() {
     env::set_var("RUST_BACKTRACE", "1");
-    let args: Vec<String> = env::args().collect();
+    let mut args = env::args().skip(1);
+
+    // Options may appear anywhere, everything else is the command and its path
+    let mut heap = Heap::new();
+    let mut disassemble = false;
+    let mut trace = false;
+    let mut output = None;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--gc-stress" => heap.stress = true,
+            "--disassemble" => disassemble = true,
+            "--trace" => trace = true,
+            "--gc-threshold" => match args.next().and_then(|bytes| bytes.parse().ok()) {
+                Some(bytes) => heap.set_threshold(bytes),
+                None => usage(),
+            },
+            "-o" => match args.next() {
+                Some(path) => output = Some(path),
+                None => usage(),
+            },
+            _ => positional.push(arg),
+        }
+    }
 
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        match run_file(&args[1]) {
+    let positional: Vec<&str> = positional.iter().map(|arg| arg.as_str()).collect();
+    match positional[..] {
+        ["compile", path] if !disassemble => compile_file(path, output),
+        _ if output.is_some() => usage(),
+        [] if !disassemble => Repl::new(heap, trace).run(),
+        ["run", path] | [path] => match run_file(path, heap, disassemble, trace) {
             Ok(()) => (),
             Err(e) => println!("Error: {e:?}"),
-        }
-    } else {
-        println!("Usage: pf [path]");
+        },
+        _ => usage(),
     }
 }
 
-// Command line interpreter
-fn repl() -> () {
+// Print how the interpreter is used and exit
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(64);
+}
+
+// File interpreter, `disassemble` prints the compiled file instead of running it.
+// Files compiled to byte code are run without compiling them again
+fn run_file(path: &str, heap: Heap, disassemble: bool, trace: bool) -> std::io::Result<()> {
+    let bytes = read_file(path);
+
     // Initialize vm
-    let mut vm = VM {
-        chunk: Chunk::new(),
-        code: Vec::new(),
-        ip: 0,
-        stack: Vec::new(),
-        globals: HashMap::new(),
-    };
+    let mut vm = VM::with_heap(heap);
+    vm.trace |= trace;
 
-    loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
-        let mut line = String::new();
-        io::stdin()
-            .read_line(&mut line)
-            .expect("Unable to read line from the REPL");
-        if line.is_empty() {
-            break;
+    let result = if bytecode::is_bytecode(&bytes) {
+        if disassemble {
+            eprintln!("Only source files can be disassembled");
+            process::exit(64);
+        }
+        vm.interpret_bytes(&bytes).map(|_| ())
+    } else {
+        let code = match String::from_utf8(bytes) {
+            Ok(code) => code,
+            Err(error) => {
+                eprint!("Unable to read file {}: {}", path, error);
+                process::exit(74);
+            }
+        };
+        if disassemble {
+            vm.disassemble(&code)
+        } else {
+            vm.interpret(&code).map(|_| ())
         }
-        vm.interpret(&line);
+    };
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(error.exit_code());
     }
+
+    Ok(())
 }
 
-// File interpreter
-fn run_file(path: &String) -> std::io::Result<()> {
-    //TODO add proper error handling
-    let code = match fs::read_to_string(path) {
-        Ok(content) => content,
+// Compile the source file `path` to byte code, written next
+// to it with a `.vbc` extension unless `output` is given
+fn compile_file(path: &str, output: Option<String>) -> () {
+    let code = match String::from_utf8(read_file(path)) {
+        Ok(code) => code,
         Err(error) => {
             eprint!("Unable to read file {}: {}", path, error);
             process::exit(74);
         }
     };
+    let output = output.unwrap_or_else(|| {
+        Path::new(path)
+            .with_extension("vbc")
+            .to_string_lossy()
+            .into_owned()
+    });
 
-    // Initialize vm
-    let mut vm = VM {
-        chunk: Chunk::new(),
-        code: Vec::new(),
-        ip: 0,
-        stack: Vec::new(),
-        globals: HashMap::new(),
-    };
-
-    vm.interpret(&code);
+    let mut vm = VM::new();
+    match vm.compile_to_bytes(&code) {
+        Ok(bytes) => {
+            if let Err(error) = fs::write(&output, bytes) {
+                eprint!("Unable to write file {}: {}", output, error);
+                process::exit(74);
+            }
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(error.exit_code());
+        }
+    }
+}
 
-    Ok(())
+fn read_file(path: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(content) => content,
+        Err(error) => {
+            eprint!("Unable to read file {}: {}", path, error);
+            process::exit(74);
+        }
+    }
 }
@@ -0,0 +1,480 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+//
+// This file contains the heap every Vera object is allocated on and its tracing
+// mark-and-sweep garbage collector. The roots are supplied by the owner of the heap,
+// the collector marks everything reachable from them and frees the rest.
+
+use std::collections::HashMap;
+
+use crate::object::{
+    Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjList, ObjMap,
+    ObjString, ObjUpvalue,
+};
+use crate::value::Value;
+
+// Heap size in bytes at which the first collection runs
+pub const GC_DEFAULT_THRESHOLD: usize = 1024 * 1024;
+// Factor the heap may grow by, relative to the surviving objects, before the next collection
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Handle of an object on the `Heap`. Handles are only valid as long as the
+/// object is reachable from the roots when a collection runs.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ObjRef(usize);
+
+struct HeapEntry {
+    obj: Obj,     // The object itself
+    marked: bool, // Reached during the current collection
+}
+
+/// Arena owning all objects created by the compiler and the VM.
+/// - `objects`: Slots of the arena, freed slots are `None` and get reused.
+/// - `strings`: Interned strings, so identical strings share one object.
+/// - `gray`: Marked objects whose references haven't been traced yet.
+/// - `bytes_allocated`: Approximate size of all live objects.
+/// - `next_gc`: Size at which the next collection runs.
+/// - `threshold`: Lower bound for `next_gc`, configurable by the embedder.
+/// - `stress`: Collect on every allocation to surface missing roots.
+pub struct Heap {
+    objects: Vec<Option<HeapEntry>>,
+    free_slots: Vec<usize>,
+    strings: HashMap<String, ObjRef>,
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    threshold: usize,
+    pub stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    // Create an empty heap with the default collection threshold
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            free_slots: Vec::new(),
+            strings: HashMap::new(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_DEFAULT_THRESHOLD,
+            threshold: GC_DEFAULT_THRESHOLD,
+            stress: false,
+        }
+    }
+
+    // Set the heap size in bytes at which the next collection runs. The heap
+    // never waits for less than this after a collection either
+    pub fn set_threshold(&mut self, bytes: usize) -> () {
+        self.threshold = bytes;
+        self.next_gc = bytes;
+    }
+
+    // Move an object onto the heap and return its handle. Strings are added
+    // to the intern table, callers check `find_string` before allocating one
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.bytes_allocated += obj.size();
+        let interned = match &obj {
+            Obj::String(s) => Some(s.chars.clone()),
+            _ => None,
+        };
+        let entry = Some(HeapEntry { obj, marked: false });
+
+        let reference = match self.free_slots.pop() {
+            Some(slot) => {
+                self.objects[slot] = entry;
+                ObjRef(slot)
+            }
+            None => {
+                self.objects.push(entry);
+                ObjRef(self.objects.len() - 1)
+            }
+        };
+
+        if let Some(chars) = interned {
+            self.strings.insert(chars, reference);
+        }
+        reference
+    }
+
+    // Find the interned string object holding `chars`
+    pub fn find_string(&self, chars: &str) -> Option<ObjRef> {
+        self.strings.get(chars).copied()
+    }
+
+    // Whether the heap grew enough for the next allocation to trigger a collection
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    // Number of live objects on the heap
+    pub fn object_count(&self) -> usize {
+        self.objects.len() - self.free_slots.len()
+    }
+
+    // Approximate size in bytes of the objects on the heap
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn get(&self, reference: ObjRef) -> &Obj {
+        match &self.objects[reference.0] {
+            Some(entry) => &entry.obj,
+            None => panic!("Use of freed object {:?}", reference),
+        }
+    }
+
+    pub fn get_mut(&mut self, reference: ObjRef) -> &mut Obj {
+        match &mut self.objects[reference.0] {
+            Some(entry) => &mut entry.obj,
+            None => panic!("Use of freed object {:?}", reference),
+        }
+    }
+
+    pub fn as_string(&self, reference: ObjRef) -> &ObjString {
+        match self.get(reference) {
+            Obj::String(s) => s,
+            _ => panic!("Object is not a string!"),
+        }
+    }
+
+    pub fn as_function(&self, reference: ObjRef) -> &ObjFunction {
+        match self.get(reference) {
+            Obj::Function(f) => f,
+            _ => panic!("Object is not a function!"),
+        }
+    }
+
+    pub fn as_closure(&self, reference: ObjRef) -> &ObjClosure {
+        match self.get(reference) {
+            Obj::Closure(c) => c,
+            _ => panic!("Object is not a closure!"),
+        }
+    }
+
+    pub fn as_upvalue(&self, reference: ObjRef) -> &ObjUpvalue {
+        match self.get(reference) {
+            Obj::Upvalue(u) => u,
+            _ => panic!("Object is not an upvalue!"),
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self, reference: ObjRef) -> &mut ObjUpvalue {
+        match self.get_mut(reference) {
+            Obj::Upvalue(u) => u,
+            _ => panic!("Object is not an upvalue!"),
+        }
+    }
+
+    pub fn as_class(&self, reference: ObjRef) -> &ObjClass {
+        match self.get(reference) {
+            Obj::Class(c) => c,
+            _ => panic!("Object is not a class!"),
+        }
+    }
+
+    pub fn as_class_mut(&mut self, reference: ObjRef) -> &mut ObjClass {
+        match self.get_mut(reference) {
+            Obj::Class(c) => c,
+            _ => panic!("Object is not a class!"),
+        }
+    }
+
+    pub fn as_instance(&self, reference: ObjRef) -> &ObjInstance {
+        match self.get(reference) {
+            Obj::Instance(i) => i,
+            _ => panic!("Object is not an instance!"),
+        }
+    }
+
+    pub fn as_instance_mut(&mut self, reference: ObjRef) -> &mut ObjInstance {
+        match self.get_mut(reference) {
+            Obj::Instance(i) => i,
+            _ => panic!("Object is not an instance!"),
+        }
+    }
+
+    pub fn as_list(&self, reference: ObjRef) -> &ObjList {
+        match self.get(reference) {
+            Obj::List(l) => l,
+            _ => panic!("Object is not a list!"),
+        }
+    }
+
+    pub fn as_list_mut(&mut self, reference: ObjRef) -> &mut ObjList {
+        match self.get_mut(reference) {
+            Obj::List(l) => l,
+            _ => panic!("Object is not a list!"),
+        }
+    }
+
+    pub fn as_map(&self, reference: ObjRef) -> &ObjMap {
+        match self.get(reference) {
+            Obj::Map(m) => m,
+            _ => panic!("Object is not a map!"),
+        }
+    }
+
+    pub fn as_map_mut(&mut self, reference: ObjRef) -> &mut ObjMap {
+        match self.get_mut(reference) {
+            Obj::Map(m) => m,
+            _ => panic!("Object is not a map!"),
+        }
+    }
+
+    pub fn as_bound_method(&self, reference: ObjRef) -> &ObjBoundMethod {
+        match self.get(reference) {
+            Obj::BoundMethod(b) => b,
+            _ => panic!("Object is not a bound method!"),
+        }
+    }
+
+    // Render a value the way `shine` prints it
+    pub fn format_value(&self, value: Value) -> String {
+        self.format_nested(value, &mut Vec::new())
+    }
+
+    // Render a value, `open` holds the lists being rendered around it
+    // so a list containing itself doesn't recurse forever
+    fn format_nested(&self, value: Value, open: &mut Vec<ObjRef>) -> String {
+        match value {
+            Value::Bool(v) => v.to_string(),
+            Value::Number(v) => format_float(v),
+            Value::Int(v) => v.to_string(),
+            Value::Nil => "Nil".to_string(),
+            Value::Object(reference) => match self.get(reference) {
+                Obj::String(s) => s.to_string(),
+                Obj::Function(f) => f.to_string(),
+                Obj::Closure(c) => self.as_function(c.function).to_string(),
+                Obj::Upvalue(_) => "upvalue".to_string(),
+                Obj::Class(c) => c.name.clone(),
+                Obj::Instance(i) => format!("<{} instance>", self.as_class(i.class).name),
+                Obj::BoundMethod(b) => {
+                    let closure = self.as_closure(b.method);
+                    self.as_function(closure.function).to_string()
+                }
+                Obj::Native(n) => n.to_string(),
+                Obj::List(_) if open.contains(&reference) => "[...]".to_string(),
+                Obj::List(l) => {
+                    open.push(reference);
+                    let items: Vec<String> = l
+                        .items
+                        .iter()
+                        .map(|&item| self.format_item(item, open))
+                        .collect();
+                    open.pop();
+                    format!("[{}]", items.join(", "))
+                }
+                Obj::Map(_) if open.contains(&reference) => "{...}".to_string(),
+                Obj::Map(m) => {
+                    open.push(reference);
+                    let entries: Vec<String> = m
+                        .entries
+                        .iter()
+                        .map(|&(key, value)| {
+                            let key = self.format_item(key, open);
+                            format!("{}: {}", key, self.format_item(value, open))
+                        })
+                        .collect();
+                    open.pop();
+                    format!("{{{}}}", entries.join(", "))
+                }
+            },
+        }
+    }
+
+    // Render a value inside a list or map. Strings are quoted to tell `["1"]` from `[1]`
+    fn format_item(&self, value: Value, open: &mut Vec<ObjRef>) -> String {
+        match value {
+            Value::Object(reference) if matches!(self.get(reference), Obj::String(_)) => {
+                format!("\"{}\"", self.as_string(reference).chars)
+            }
+            _ => self.format_nested(value, open),
+        }
+    }
+
+    // Mark the object a value refers to, if any
+    pub fn mark_value(&mut self, value: Value) -> () {
+        if let Value::Object(reference) = value {
+            self.mark_object(reference);
+        }
+    }
+
+    // Mark an object as reachable and queue it for tracing
+    pub fn mark_object(&mut self, reference: ObjRef) -> () {
+        let entry = self.objects[reference.0]
+            .as_mut()
+            .unwrap_or_else(|| panic!("Marking freed object {:?}", reference));
+
+        if entry.marked {
+            return;
+        }
+        entry.marked = true;
+        self.gray.push(reference);
+    }
+
+    // Mark everything reachable from the already marked objects
+    pub fn trace_references(&mut self) -> () {
+        while let Some(reference) = self.gray.pop() {
+            self.blacken_object(reference);
+        }
+    }
+
+    // Mark all objects referenced by a marked object
+    fn blacken_object(&mut self, reference: ObjRef) -> () {
+        let mut values = Vec::new();
+        let mut objects = Vec::new();
+
+        match self.get(reference) {
+            Obj::String(_) => (),
+            Obj::Function(f) => values.extend(f.chunk.constants.array.iter().copied()),
+            Obj::Closure(c) => {
+                objects.push(c.function);
+                objects.extend(c.upvalues.iter().copied());
+            }
+            Obj::Upvalue(ObjUpvalue::Closed(value)) => values.push(*value),
+            // Open upvalues point at stack slots, which are roots anyway
+            Obj::Upvalue(ObjUpvalue::Open(_)) => (),
+            Obj::Class(c) => {
+                objects.extend(c.methods.keys().copied());
+                objects.extend(c.methods.values().copied());
+            }
+            Obj::Instance(i) => {
+                objects.push(i.class);
+                objects.extend(i.fields.keys().copied());
+                values.extend(i.fields.values().copied());
+            }
+            Obj::BoundMethod(b) => {
+                values.push(b.receiver);
+                objects.push(b.method);
+            }
+            Obj::Native(_) => (),
+            Obj::List(l) => values.extend(l.items.iter().copied()),
+            Obj::Map(m) => {
+                for &(key, value) in m.entries.iter() {
+                    values.push(key);
+                    values.push(value);
+                }
+            }
+        }
+
+        for value in values {
+            self.mark_value(value);
+        }
+        for object in objects {
+            self.mark_object(object);
+        }
+    }
+
+    // Free every object that wasn't marked and clear the marks of the survivors.
+    // Objects like classes and instances grow after they are allocated, so the
+    // heap size is recounted from the survivors. The intern table doesn't keep
+    // strings alive, freed strings are dropped from it
+    pub fn sweep(&mut self) -> () {
+        self.bytes_allocated = 0;
+        for (slot, object) in self.objects.iter_mut().enumerate() {
+            match object {
+                Some(entry) if entry.marked => {
+                    entry.marked = false;
+                    self.bytes_allocated += entry.obj.size();
+                }
+                Some(entry) => {
+                    if let Obj::String(s) = &entry.obj {
+                        self.strings.remove(&s.chars);
+                    }
+                    *object = None;
+                    self.free_slots.push(slot);
+                }
+                None => (),
+            }
+        }
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(self.threshold);
+    }
+}
+
+// Render a float, whole floats keep a `.0` so they don't print like integers
+fn format_float(value: f64) -> String {
+    if value.is_finite() && value.fract() == 0.0 {
+        format!("{:.1}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+// ### TESTS ###
+
+#[cfg(test)]
+mod tests {
+
+    use crate::memory::Heap;
+    use crate::object::{Obj, ObjClass, ObjInstance, ObjString};
+    use crate::value::Value;
+
+    fn string(heap: &mut Heap, chars: &str) -> Value {
+        Value::Object(heap.alloc(Obj::String(ObjString {
+            chars: chars.to_string(),
+        })))
+    }
+
+    #[test]
+    fn test_sweep_frees_unreachable_objects() {
+        let mut heap = Heap::new();
+        let kept = string(&mut heap, "Shine on");
+        string(&mut heap, "you crazy diamond");
+
+        heap.mark_value(kept);
+        heap.trace_references();
+        heap.sweep();
+
+        assert_eq!(heap.object_count(), 1);
+        assert_eq!(heap.format_value(kept), "Shine on");
+
+        // Freed slots are reused by later allocations
+        string(&mut heap, "Wish you were here");
+        assert_eq!(heap.object_count(), 2);
+    }
+
+    #[test]
+    fn test_trace_follows_references() {
+        let mut heap = Heap::new();
+        let class = heap.alloc(Obj::Class(ObjClass::new("Band".to_string())));
+        let mut instance = ObjInstance::new(class);
+        let field = string(&mut heap, "name");
+        let name = string(&mut heap, "Pink Floyd");
+        if let Value::Object(field) = field {
+            instance.fields.insert(field, name);
+        }
+        let instance = Value::Object(heap.alloc(Obj::Instance(instance)));
+
+        heap.mark_value(instance);
+        heap.trace_references();
+        heap.sweep();
+
+        // The class and the field survive through the instance
+        assert_eq!(heap.object_count(), 4);
+        assert_eq!(heap.format_value(instance), "<Band instance>");
+        assert_eq!(heap.format_value(name), "Pink Floyd");
+    }
+
+    #[test]
+    fn test_sweep_drops_freed_strings_from_intern_table() {
+        let mut heap = Heap::new();
+        let kept = string(&mut heap, "Hey you");
+        string(&mut heap, "Goodbye cruel world");
+        assert!(heap.find_string("Goodbye cruel world").is_some());
+
+        heap.mark_value(kept);
+        heap.trace_references();
+        heap.sweep();
+
+        assert_eq!(heap.find_string("Hey you").map(Value::Object), Some(kept));
+        assert_eq!(heap.find_string("Goodbye cruel world"), None);
+    }
+}
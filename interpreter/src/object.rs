@@ -2,6 +2,60 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file
 
+use std::collections::HashMap;
+use std::mem;
+
+use crate::chunk::{Chunk, OpCode};
+use crate::memory::{Heap, ObjRef};
+use crate::value::Value;
+use crate::vm::VM;
+
+// Object living on the VM heap. Values refer to
+// objects through an `ObjRef` handle
+pub enum Obj {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+    List(ObjList),
+    Map(ObjMap),
+}
+
+impl Obj {
+    // Approximate number of bytes owned by the object, used to decide
+    // when the garbage collector runs
+    pub fn size(&self) -> usize {
+        let owned = match self {
+            Obj::String(s) => s.chars.capacity(),
+            Obj::Function(f) => {
+                f.chunk.code.capacity() * mem::size_of::<OpCode>()
+                    + f.chunk.constants.array.capacity() * mem::size_of::<Value>()
+                    + f.chunk.line.capacity() * mem::size_of::<usize>()
+                    + f.name.capacity()
+            }
+            Obj::Closure(c) => c.upvalues.capacity() * mem::size_of::<ObjRef>(),
+            Obj::Upvalue(_) => 0,
+            Obj::Class(c) => {
+                c.name.capacity() + c.methods.capacity() * mem::size_of::<(ObjRef, ObjRef)>()
+            }
+            Obj::Instance(i) => i.fields.capacity() * mem::size_of::<(ObjRef, Value)>(),
+            Obj::BoundMethod(_) => 0,
+            Obj::Native(n) => n.name.capacity(),
+            Obj::List(l) => l.items.capacity() * mem::size_of::<Value>(),
+            Obj::Map(m) => {
+                m.entries.capacity() * mem::size_of::<(Value, Value)>()
+                    + m.indices.capacity() * mem::size_of::<(MapKey, usize)>()
+            }
+        };
+
+        mem::size_of::<Obj>() + owned
+    }
+}
+
 #[derive(PartialEq)]
 pub struct ObjString {
     pub chars: String,
@@ -22,3 +76,199 @@ impl Clone for ObjString {
         }
     }
 }
+
+// Variable captured by a procedure, resolved when its closure is created
+#[derive(Copy, Clone, PartialEq)]
+pub struct Upvalue {
+    pub index: u8,      // Stack slot or upvalue index in the enclosing procedure
+    pub is_local: bool, // Whether `index` is a local of the enclosing procedure
+}
+
+// Compiled `brick` procedure. The top level script is compiled
+// into a function as well, it is the only one without a name
+pub struct ObjFunction {
+    pub arity: usize,           // Number of parameters the procedure expects
+    pub upvalues: Vec<Upvalue>, // Variables captured from enclosing procedures
+    pub chunk: Chunk,           // Byte code of the procedure body
+    pub name: String,           // Name of the procedure, empty for the script
+}
+
+impl ObjFunction {
+    // Create a new empty function
+    pub fn new(name: String) -> ObjFunction {
+        ObjFunction {
+            arity: 0,
+            upvalues: Vec::new(),
+            chunk: Chunk::new(),
+            name,
+        }
+    }
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "<script>")
+        } else {
+            write!(f, "<brick {}>", self.name)
+        }
+    }
+}
+
+// Captured variable. It points at the variable's stack slot while the
+// variable is in scope and holds the value itself once it is closed
+pub enum ObjUpvalue {
+    Open(usize),   // Index of the variable on the value stack
+    Closed(Value), // Value of the variable after it left the stack
+}
+
+// Procedure together with the variables it captured. Every
+// procedure is wrapped in a closure before it can be called
+pub struct ObjClosure {
+    pub function: ObjRef,      // `ObjFunction` to execute
+    pub upvalues: Vec<ObjRef>, // Captured variables, each an `ObjUpvalue`
+}
+
+// Name of the method called when a class is instantiated
+pub const INITIALIZER: &str = "init";
+
+// Class declared with the `class` keyword
+pub struct ObjClass {
+    pub name: String,                     // Name of the class
+    pub methods: HashMap<ObjRef, ObjRef>, // Closures of the methods, keyed by their interned name
+}
+
+impl ObjClass {
+    // Create a new class without methods
+    pub fn new(name: String) -> ObjClass {
+        ObjClass {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+// Instance of a class, created by calling the class
+pub struct ObjInstance {
+    pub class: ObjRef,                  // `ObjClass` the instance was created from
+    pub fields: HashMap<ObjRef, Value>, // Fields set on the instance, keyed by their interned name
+}
+
+impl ObjInstance {
+    // Create a new instance without fields
+    pub fn new(class: ObjRef) -> ObjInstance {
+        ObjInstance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+// Method accessed on an instance, remembers the instance as `this`
+pub struct ObjBoundMethod {
+    pub receiver: Value, // Instance the method was accessed on
+    pub method: ObjRef,  // `ObjClosure` of the method to call
+}
+
+// List created with a `[...]` literal
+pub struct ObjList {
+    pub items: Vec<Value>,
+}
+
+// Error for map keys `MapKey` can't represent
+pub const UNHASHABLE_KEY: &str = "Map keys must be strings, numbers, booleans or money.";
+
+// Hashable form of a map key. Strings are interned, so equal strings share
+// their handle. Floats that equal an integer are stored as that integer,
+// so `1` and `1.0` are the same key, other floats are hashed by their bits
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Bool(bool),
+    Number(u64),
+    Int(i64),
+    String(ObjRef),
+    Nil,
+}
+
+impl MapKey {
+    // Key for `value`, `None` for values that can't be map keys
+    pub fn new(value: Value, heap: &Heap) -> Option<MapKey> {
+        match value {
+            Value::Bool(v) => Some(MapKey::Bool(v)),
+            Value::Int(v) => Some(MapKey::Int(v)),
+            // `-0` is converted to `0` as well
+            Value::Number(v) => match value.as_int() {
+                Some(v) => Some(MapKey::Int(v)),
+                None => Some(MapKey::Number(v.to_bits())),
+            },
+            Value::Nil => Some(MapKey::Nil),
+            Value::Object(reference) => match heap.get(reference) {
+                Obj::String(_) => Some(MapKey::String(reference)),
+                _ => None,
+            },
+        }
+    }
+}
+
+// Map created with a `{key: value}` literal. Entries are kept
+// in the order their keys were first inserted
+pub struct ObjMap {
+    pub entries: Vec<(Value, Value)>, // Keys and their values
+    indices: HashMap<MapKey, usize>,  // Position of every key in `entries`
+}
+
+impl ObjMap {
+    // Create a new empty map
+    pub fn new() -> ObjMap {
+        ObjMap {
+            entries: Vec::new(),
+            indices: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: MapKey) -> Option<Value> {
+        self.indices.get(&key).map(|&index| self.entries[index].1)
+    }
+
+    // Set the value of `key`, which is `key_value` as a Vera value
+    pub fn insert(&mut self, key: MapKey, key_value: Value, value: Value) -> () {
+        match self.indices.get(&key) {
+            Some(&index) => self.entries[index].1 = value,
+            None => {
+                self.indices.insert(key, self.entries.len());
+                self.entries.push((key_value, value));
+            }
+        }
+    }
+
+    // Remove `key`, returning its value if it was in the map
+    pub fn remove(&mut self, key: MapKey) -> Option<Value> {
+        let index = self.indices.remove(&key)?;
+        let (_, value) = self.entries.remove(index);
+
+        // The entries after the removed one moved down by one
+        for position in self.indices.values_mut() {
+            if *position > index {
+                *position -= 1;
+            }
+        }
+        Some(value)
+    }
+}
+
+// Rust function exposed to Vera code. It gets the VM it was called
+// from and its arguments, an `Err` becomes a runtime error
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+// Native function registered with `VM::define_native`
+pub struct ObjNative {
+    pub name: String,       // Name of the global the function is bound to
+    pub arity: usize,       // Number of arguments the function expects
+    pub function: NativeFn, // Function to call
+}
+
+impl fmt::Display for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native {}>", self.name)
+    }
+}
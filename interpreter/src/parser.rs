@@ -5,13 +5,16 @@
 // This file contains the implementation the parser for Vera. It defines parsing rules
 // and precedence, manages compiler state, and handles expressions, variables, and control flow structures.
 
-use crate::object::ObjString;
-use std::{collections::HashMap, u16};
+use crate::object::{Obj, ObjFunction, Upvalue, INITIALIZER};
+use std::collections::HashMap;
 
 use crate::{
-    chunk::{Chunk, OpCode},
+    chunk::{Chunk, OpCode, CONSTANTS_MAX},
+    error::CompileError,
     lexer::{Token, TokenType},
+    memory::ObjRef,
     value::Value,
+    vm::VM,
 };
 
 /// Enum representing the different levels of operator precedence.
@@ -24,9 +27,13 @@ enum Precedence {
     PrecAnd,        // Logical AND: `and`
     PrecEquality,   // Equality comparisons: `==`, `!=`
     PrecComparison, // Comparisons: `<`, `>`, `<=`, `>=`
+    PrecBitOr,      // Bitwise OR: `|`
+    PrecBitXor,     // Bitwise XOR: `~`
+    PrecBitAnd,     // Bitwise AND: `&`
     PrecTerm,       // Addition and subtraction: `+`, `-`
-    PrecFactor,     // Multiplication and division: `*`, `/`
-    PrecUnary,      // Unary operators: `-`, `!`
+    PrecFactor,     // Multiplication, division and modulo: `*`, `/`, `%`
+    PrecPower,      // Exponentiation: `^`
+    PrecUnary,      // Unary operators: `-`, `!`, `~`
     PrecCall,       // Function calls and property access: `()`, `.`
     PrecPrimary,    // Primary expressions (identifiers, literals)
 }
@@ -39,9 +46,13 @@ impl Precedence {
             Precedence::PrecOr => Precedence::PrecAnd,
             Precedence::PrecAnd => Precedence::PrecEquality,
             Precedence::PrecEquality => Precedence::PrecComparison,
-            Precedence::PrecComparison => Precedence::PrecTerm,
+            Precedence::PrecComparison => Precedence::PrecBitOr,
+            Precedence::PrecBitOr => Precedence::PrecBitXor,
+            Precedence::PrecBitXor => Precedence::PrecBitAnd,
+            Precedence::PrecBitAnd => Precedence::PrecTerm,
             Precedence::PrecTerm => Precedence::PrecFactor,
-            Precedence::PrecFactor => Precedence::PrecUnary,
+            Precedence::PrecFactor => Precedence::PrecPower,
+            Precedence::PrecPower => Precedence::PrecUnary,
             Precedence::PrecUnary => Precedence::PrecCall,
             Precedence::PrecCall => Precedence::PrecPrimary,
             Precedence::PrecPrimary => Precedence::PrecNone,
@@ -82,76 +93,139 @@ impl<'p> ParseRule<'p> {
 struct Local {
     name: Token,
     depth: isize,
+    is_captured: bool, // Captured by a nested procedure, must be closed instead of popped
+}
+
+/// The kind of code a `Compiler` is emitting. The top level script is compiled
+/// like the body of a procedure, but can't `goodbye` out of it.
+#[derive(Copy, Clone, PartialEq)]
+enum FunctionType {
+    Function,    // Body of a `brick` procedure
+    Initializer, // Body of a class initializer, always returns `this`
+    Method,      // Body of a method, `this` lives in slot zero
+    Script,      // Top level code
 }
 
 /// The `Compiler` struct is responsible for managing local variables and scope depth during
 /// the parsing process. It tracks local variables in a stack and manages entering and exiting
-/// scope levels.
+/// scope levels. Every `brick` body gets its own `Compiler`, which owns the function being emitted.
+/// The `Parser` keeps the compilers of all enclosing procedures on a stack to resolve captured variables.
 struct Compiler {
-    locals: Vec<Local>, // Stack of local variables
-    scope_depth: isize, // Current depth of nested scopes
-}
-
-impl Clone for Compiler {
-    fn clone(&self) -> Self {
-        Self {
-            locals: self.locals.clone(),
-            scope_depth: self.scope_depth,
-        }
-    }
+    function: ObjFunction,       // Procedure the byte code is emitted into
+    function_type: FunctionType, // Kind of code being compiled
+    locals: Vec<Local>,          // Stack of local variables
+    scope_depth: isize,          // Current depth of nested scopes
 }
 
 impl Compiler {
-    fn new() -> Self {
-        Compiler {
+    fn new(function_type: FunctionType, name: String) -> Self {
+        let mut compiler = Compiler {
+            function: ObjFunction::new(name),
+            function_type,
             locals: Vec::new(),
             scope_depth: 0,
-        }
-    }
+        };
 
-    fn reset_compiler(&mut self) -> () {
-        self.locals.clear();
-        self.scope_depth = 0;
+        // Stack slot zero holds the procedure being called, or the
+        // receiver for methods. Claim it so locals start at slot one
+        let lexeme = match function_type {
+            FunctionType::Initializer | FunctionType::Method => "this".to_string(),
+            _ => String::new(),
+        };
+        compiler.locals.push(Local {
+            name: Token {
+                _type: TokenType::TokenIdentifier,
+                lexeme,
+                line: 0,
+                col: 0,
+            },
+            depth: 0,
+            is_captured: false,
+        });
+
+        compiler
     }
 }
 
 /// The `Parser` struct is the core of the parsing process. It holds the list of tokens,
-/// manages the state of the current parsing operation, and emits bytecode to a `Chunk`.
+/// manages the state of the current parsing operation, and emits bytecode into the chunk
+/// of the function being compiled.
 /// - `tokens`: The token stream generated by the lexer.
-/// - `chunk`: The bytecode chunk being emitted.
-/// - `had_error`: A flag indicating if an error occurred during parsing.
+/// - `errors`: Every error found during parsing, in source order.
 /// - `panic_mode`: A flag indicating if the parser is in panic mode after an error.
-/// - `current_compiler`: Manages the state of the current compiler (locals, scope depth).
+/// - `compilers`: Stack of compilers of the procedures enclosing the current token, the last one
+///   is the current compiler (function, locals, scope depth).
+/// - `class_depth`: How many class bodies are being compiled, `this` is only valid inside one.
+/// - `vm`: The VM whose heap the strings and procedures of the program are allocated on.
 pub struct Parser<'c> {
     current: usize,                           // Index of the current token being parsed
     tokens: &'c Vec<Token>,                   // List of tokens to be parsed
-    pub chunk: &'c mut Chunk,                 // Bytecode chunk to emit instructions
-    had_error: bool,                          // Tracks if any error occurred during parsing
+    errors: Vec<CompileError>,                // Errors found during parsing
     panic_mode: bool,                         // Tracks if the parser is in panic mode
     rules: HashMap<TokenType, ParseRule<'c>>, // Parsing rules for each token type
-    current_compiler: Compiler, // State of the current compiler (local variables, scope depth)
+    compilers: Vec<Compiler>, // Compilers of the enclosing procedures, innermost last
+    class_depth: usize,       // Number of class declarations enclosing the current token
+    vm: &'c mut VM,           // VM owning the heap objects are allocated on
+    returns_last_expression: bool, // Whether a final expression statement is the script's result
+    last_expression_pop: Option<usize>, // Offset of the `OpPop` ending the last top-level expression statement
 }
 
 impl<'c> Parser<'c> {
-    pub fn new(tokens: &'c Vec<Token>, chunk: &'c mut Chunk) -> Self {
+    pub fn new(tokens: &'c Vec<Token>, vm: &'c mut VM) -> Self {
         let mut rules = HashMap::new();
         let mut rule = |kind, prefix, infix, precedence| {
             rules.insert(kind, ParseRule::new(prefix, infix, precedence));
         };
 
-        //TODO add TokenMinusMinus and TokenPlusPlus
+        // Postfix `a++` is compiled with the variable it follows, the infix
+        // rule only runs when the operand isn't a variable
+        rule(
+            TokenType::TokenPlusPlus,
+            Some(Parser::prefix_increment),
+            Some(Parser::invalid_increment),
+            Precedence::PrecCall,
+        );
+        rule(
+            TokenType::TokenMinusMinus,
+            Some(Parser::prefix_increment),
+            Some(Parser::invalid_increment),
+            Precedence::PrecCall,
+        );
 
         rule(
             TokenType::TokenLeftParen,
             Some(Parser::grouping),
+            Some(Parser::call),
+            Precedence::PrecCall,
+        );
+        rule(TokenType::TokenRightParen, None, None, Precedence::PrecNone);
+        rule(
+            TokenType::TokenLeftBrace,
+            Some(Parser::map),
             None,
             Precedence::PrecNone,
         );
-        rule(TokenType::TokenRightParen, None, None, Precedence::PrecNone);
-        rule(TokenType::TokenLeftBrace, None, None, Precedence::PrecNone);
         rule(TokenType::TokenRightBrace, None, None, Precedence::PrecNone);
+        rule(
+            TokenType::TokenLeftBracket,
+            Some(Parser::list),
+            Some(Parser::index),
+            Precedence::PrecCall,
+        );
+        rule(
+            TokenType::TokenRightBracket,
+            None,
+            None,
+            Precedence::PrecNone,
+        );
         rule(TokenType::TokenComma, None, None, Precedence::PrecNone);
-        rule(TokenType::TokenDot, None, None, Precedence::PrecNone);
+        rule(TokenType::TokenColon, None, None, Precedence::PrecNone);
+        rule(
+            TokenType::TokenDot,
+            None,
+            Some(Parser::dot),
+            Precedence::PrecCall,
+        );
         rule(
             TokenType::TokenMinus,
             Some(Parser::unary),
@@ -168,7 +242,7 @@ impl<'c> Parser<'c> {
             TokenType::TokenPow,
             None,
             Some(Parser::binary),
-            Precedence::PrecTerm,
+            Precedence::PrecPower,
         );
         rule(
             TokenType::TokenShiftLeft,
@@ -195,6 +269,31 @@ impl<'c> Parser<'c> {
             Some(Parser::binary),
             Precedence::PrecFactor,
         );
+        rule(
+            TokenType::TokenPercent,
+            None,
+            Some(Parser::binary),
+            Precedence::PrecFactor,
+        );
+        rule(
+            TokenType::TokenAmpersand,
+            None,
+            Some(Parser::binary),
+            Precedence::PrecBitAnd,
+        );
+        rule(
+            TokenType::TokenPipe,
+            None,
+            Some(Parser::binary),
+            Precedence::PrecBitOr,
+        );
+        // `~` is bitwise NOT as a prefix and XOR between two operands
+        rule(
+            TokenType::TokenTilde,
+            Some(Parser::unary),
+            Some(Parser::binary),
+            Precedence::PrecBitXor,
+        );
         rule(
             TokenType::TokenBang,
             Some(Parser::unary),
@@ -208,6 +307,11 @@ impl<'c> Parser<'c> {
             Precedence::PrecEquality,
         );
         rule(TokenType::TokenEqual, None, None, Precedence::PrecNone);
+        rule(TokenType::TokenPlusEqual, None, None, Precedence::PrecNone);
+        rule(TokenType::TokenMinusEqual, None, None, Precedence::PrecNone);
+        rule(TokenType::TokenStarEqual, None, None, Precedence::PrecNone);
+        rule(TokenType::TokenSlashEqual, None, None, Precedence::PrecNone);
+        rule(TokenType::TokenPowEqual, None, None, Precedence::PrecNone);
         rule(
             TokenType::TokenEqualEqual,
             None,
@@ -282,17 +386,9 @@ impl<'c> Parser<'c> {
         );
         rule(TokenType::TokenClass, None, None, Precedence::PrecNone);
         rule(TokenType::TokenElse, None, None, Precedence::PrecNone);
-        rule(
-            TokenType::TokenFalse,
-            Some(Parser::literal),
-            None,
-            Precedence::PrecNone,
-        );
-        rule(TokenType::TokenFor, None, None, Precedence::PrecNone);
         rule(TokenType::TokenFor, None, None, Precedence::PrecNone);
         rule(TokenType::TokenFun, None, None, Precedence::PrecNone);
         rule(TokenType::TokenIf, None, None, Precedence::PrecNone);
-        rule(TokenType::TokenNil, None, None, Precedence::PrecNone);
         rule(
             TokenType::TokenOr,
             None,
@@ -301,7 +397,12 @@ impl<'c> Parser<'c> {
         );
         rule(TokenType::TokenPrint, None, None, Precedence::PrecNone);
         rule(TokenType::TokenReturn, None, None, Precedence::PrecNone);
-        rule(TokenType::TokenTrue, None, None, Precedence::PrecNone);
+        rule(
+            TokenType::TokenThis,
+            Some(Parser::this_),
+            None,
+            Precedence::PrecNone,
+        );
         rule(TokenType::TokenVar, None, None, Precedence::PrecNone);
         rule(TokenType::TokenWhile, None, None, Precedence::PrecNone);
         rule(TokenType::TokenError, None, None, Precedence::PrecNone);
@@ -310,37 +411,68 @@ impl<'c> Parser<'c> {
         return Parser {
             tokens,
             current: 0,
-            had_error: false,
+            errors: Vec::new(),
             panic_mode: false,
-            chunk,
             rules,
-            current_compiler: Compiler::new(),
+            compilers: vec![Compiler::new(FunctionType::Script, String::new())],
+            class_depth: 0,
+            vm,
+            returns_last_expression: false,
+            last_expression_pop: None,
         };
     }
+
+    /// Makes the value of an expression statement ending the script its result
+    /// instead of `nil`, so the REPL can show the value of what was typed.
+    pub fn returning_last_expression(mut self) -> Self {
+        self.returns_last_expression = true;
+        self
+    }
+
     /// Compiles the provided tokens into bytecode.
     /// It iterates over all tokens, parsing declarations and statements, and emits corresponding bytecode.
-    /// Returns `true` if compilation was successful without errors.
-    pub fn compile(&mut self) -> bool {
-        self.current_compiler.reset_compiler();
-
+    /// Returns the top level script as a function on the VM heap, or every error found
+    /// if compilation failed.
+    pub fn compile(mut self) -> Result<ObjRef, Vec<CompileError>> {
         while !self.match_token(TokenType::TokenEOF) {
             self.declaration(); // Parse top-level declaration
         }
 
-        self.end_compiler();
-        return !self.had_error; // Compilation success if no errors
+        // Only an expression statement ending the script is its result, not one
+        // that is the body of an `outThere?` or a loop ending the script
+        let end = self.current_chunk().code.len();
+        if self.returns_last_expression && self.last_expression_pop.map(|pop| pop + 1) == Some(end)
+        {
+            // Return the value instead of popping it
+            let chunk = self.current_chunk();
+            chunk.code.pop();
+            chunk.line.pop();
+            self.emit_byte(OpCode::OpReturn);
+        } else {
+            self.end_compiler();
+        }
+
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        let script = self.compilers.pop().expect("Missing script compiler");
+        Ok(self.alloc(Obj::Function(script.function)))
     }
 
-    /// Parses a top-level declaration. This could be a variable declaration or a statement.
+    /// Parses a top-level declaration. This could be a class, procedure, variable declaration or a statement.
     /// If an error occurs, the parser synchronizes to recover from the panic mode.
     fn declaration(&mut self) -> () {
-        if self.match_token(TokenType::TokenVar) {
+        if self.match_token(TokenType::TokenClass) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::TokenFun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::TokenVar) {
             self.var_declaration();
         } else {
             self.statement();
         }
 
-        if self.panic_mode == true {
+        if self.panic_mode {
             self.synchronize(); // Recover from error by skipping to next valid statement
         }
     }
@@ -356,6 +488,8 @@ impl<'c> Parser<'c> {
             self.for_statement();
         } else if self.match_token(TokenType::TokenIf) {
             self.if_statement();
+        } else if self.match_token(TokenType::TokenReturn) {
+            self.return_statement();
         } else if self.match_token(TokenType::TokenWhile) {
             self.while_statement();
         } else {
@@ -366,7 +500,12 @@ impl<'c> Parser<'c> {
     fn expression_statement(&mut self) -> () {
         self.expression();
         self.consume(TokenType::TokenSemicolon, "Expected ';' after expression");
-        self.emit_byte(OpCode::OpPop);
+        let pop = self.emit_byte(OpCode::OpPop);
+
+        let top_level = self.compilers.len() == 1 && self.current_compiler().scope_depth == 0;
+        if top_level && !self.panic_mode {
+            self.last_expression_pop = Some(pop);
+        }
     }
 
     fn for_statement(&mut self) -> () {
@@ -398,7 +537,7 @@ impl<'c> Parser<'c> {
         }
 
         // Increment
-        if !self.match_token(TokenType::TokenLeftParen) {
+        if !self.match_token(TokenType::TokenRightParen) {
             let body_jump = self.emit_byte(OpCode::OpJump(0xffff));
             let increment_start = self.start_loop();
 
@@ -419,25 +558,36 @@ impl<'c> Parser<'c> {
         self.end_scope();
     }
 
+    // Compile an `outThere?` statement with its `anybody outThere?` branches.
+    // Each branch jumps to the end of the whole chain once its body ran
     fn if_statement(&mut self) -> () {
-        self.consume(TokenType::TokenLeftParen, "Expected '(' after 'outThere'.");
-        self.expression();
-        self.consume(TokenType::TokenRightParen, "Expected ')' after condition.");
+        let mut end_jumps = Vec::new();
+        loop {
+            self.consume(TokenType::TokenLeftParen, "Expected '(' after 'outThere?'.");
+            self.expression();
+            self.consume(TokenType::TokenRightParen, "Expected ')' after condition.");
 
-        let then_jump = self.emit_byte(OpCode::OpJumpIfFalse(0xffff));
-        self.emit_byte(OpCode::OpPop);
-        self.statement();
+            let then_jump = self.emit_byte(OpCode::OpJumpIfFalse(0xffff));
+            self.emit_byte(OpCode::OpPop);
+            self.statement();
 
-        let else_jump = self.emit_byte(OpCode::OpJump(0xffff));
+            end_jumps.push(self.emit_byte(OpCode::OpJump(0xffff)));
 
-        self.patch_jump(then_jump);
-        self.emit_byte(OpCode::OpPop);
+            self.patch_jump(then_jump);
+            self.emit_byte(OpCode::OpPop);
 
-        if self.match_token(TokenType::TokenElse) {
-            self.statement();
+            if !self.match_token(TokenType::TokenElse) {
+                break;
+            }
+            if !self.match_token(TokenType::TokenIf) {
+                self.statement();
+                break;
+            }
         }
 
-        self.patch_jump(else_jump);
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
     }
 
     fn print_statement(&mut self) -> () {
@@ -446,6 +596,27 @@ impl<'c> Parser<'c> {
         self.emit_byte(OpCode::OpPrint);
     }
 
+    fn return_statement(&mut self) -> () {
+        if self.current_compiler().function_type == FunctionType::Script {
+            self.error("Can't 'goodbye' from top-level code.");
+        }
+
+        if self.match_token(TokenType::TokenSemicolon) {
+            self.emit_return();
+        } else {
+            if self.current_compiler().function_type == FunctionType::Initializer {
+                self.error("Can't 'goodbye' with a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(
+                TokenType::TokenSemicolon,
+                "Expected ';' after return value.",
+            );
+            self.emit_byte(OpCode::OpReturn);
+        }
+    }
+
     fn while_statement(&mut self) -> () {
         let loop_start = self.start_loop();
 
@@ -462,6 +633,90 @@ impl<'c> Parser<'c> {
         self.emit_byte(OpCode::OpPop);
     }
 
+    /// Compiles a class declaration. The class is created empty and every method
+    /// in its body is attached to it with its own `OpMethod` instruction.
+    fn class_declaration(&mut self) -> () {
+        self.consume(TokenType::TokenIdentifier, "Expected class name.");
+        let class_name = self.tokens[self.current - 1].clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_byte(OpCode::OpClass(name_constant));
+        self.define_variable(name_constant);
+
+        // Load the class back onto the stack so methods can be bound to it
+        self.class_depth += 1;
+        self.named_variable(&class_name, false);
+
+        self.consume(TokenType::TokenLeftBrace, "Expected '{' before class body.");
+        while !self.check(TokenType::TokenRightBrace) && !self.check(TokenType::TokenEOF) {
+            self.method();
+        }
+        self.consume(TokenType::TokenRightBrace, "Expected '}' after class body.");
+
+        self.emit_byte(OpCode::OpPop);
+        self.class_depth -= 1;
+    }
+
+    fn method(&mut self) -> () {
+        self.consume(TokenType::TokenFun, "Expected 'brick' before method.");
+        self.consume(TokenType::TokenIdentifier, "Expected method name.");
+        let constant = self.identifier_constant(&self.tokens[self.current - 1]);
+
+        let function_type = if self.tokens[self.current - 1].lexeme == INITIALIZER {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+
+        self.emit_byte(OpCode::OpMethod(constant));
+    }
+
+    fn fun_declaration(&mut self) -> () {
+        let global = self.parse_variable("Expected brick name.");
+        // A procedure may refer to itself inside its own body
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    /// Compiles the parameter list and body of a procedure with a fresh `Compiler`
+    /// and emits a closure over the resulting function into the enclosing chunk.
+    fn function(&mut self, function_type: FunctionType) -> () {
+        let name = self.tokens[self.current - 1].lexeme.clone();
+        self.compilers.push(Compiler::new(function_type, name));
+        self.begin_scope();
+
+        self.consume(TokenType::TokenLeftParen, "Expected '(' after brick name.");
+        if !self.check(TokenType::TokenRightParen) {
+            loop {
+                self.current_compiler().function.arity += 1;
+                if self.current_compiler().function.arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                let constant = self.parse_variable("Expected parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_token(TokenType::TokenComma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::TokenRightParen, "Expected ')' after parameters.");
+        self.consume(TokenType::TokenLeftBrace, "Expected '{' before brick body.");
+        self.block();
+
+        self.end_compiler();
+        let compiler = self.compilers.pop().expect("Missing procedure compiler");
+
+        // The closure captures the variables listed in the function's upvalues
+        let function = self.alloc(Obj::Function(compiler.function));
+        let constant = self.make_constant(Value::Object(function));
+        self.emit_byte(OpCode::OpClosure(constant));
+    }
+
     fn var_declaration(&mut self) -> () {
         let global = self.parse_variable("Epxected variable name.");
 
@@ -476,11 +731,11 @@ impl<'c> Parser<'c> {
         self.define_variable(global);
     }
 
-    fn parse_variable(&mut self, msg: &'c str) -> u8 {
+    fn parse_variable(&mut self, msg: &'c str) -> u32 {
         self.consume(TokenType::TokenIdentifier, msg);
 
         self.declare_variable();
-        if self.current_compiler.scope_depth > 0 {
+        if self.current_compiler().scope_depth > 0 {
             return 0;
         }
 
@@ -488,24 +743,29 @@ impl<'c> Parser<'c> {
     }
 
     fn mark_initialized(&mut self) -> () {
-        self.current_compiler
+        let scope_depth = self.current_compiler().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+
+        self.current_compiler()
             .locals
             .last_mut()
             .unwrap_or_else(|| panic!("Could not get last local in mark_initialized"))
-            .depth = self.current_compiler.scope_depth;
+            .depth = scope_depth;
     }
 
-    fn define_variable(&mut self, global: u8) -> () {
-        if self.current_compiler.scope_depth > 0 {
+    fn define_variable(&mut self, global: u32) -> () {
+        if self.current_compiler().scope_depth > 0 {
             self.mark_initialized();
             return;
         }
 
-        self.emit_byte(OpCode::OpDefineGlobal(global));
+        self.emit_constant_op(global, OpCode::OpDefineGlobal, OpCode::OpDefineGlobalLong);
     }
 
     fn and_(&mut self, _can_assign: bool) -> () {
-        let end_jump = self.emit_jump(OpCode::OpJumpIfFalse(0xff)) as usize;
+        let end_jump = self.emit_byte(OpCode::OpJumpIfFalse(0xffff));
 
         self.emit_byte(OpCode::OpPop);
         self.parse_precedence(Precedence::PrecAnd);
@@ -514,15 +774,16 @@ impl<'c> Parser<'c> {
     }
 
     fn declare_variable(&mut self) -> () {
-        if self.current_compiler.scope_depth == 0 {
+        let scope_depth = self.current_compiler().scope_depth;
+        if scope_depth == 0 {
             return;
         }
 
         let name = self.tokens[self.current - 1].clone();
 
         // Check for duplicate names in scope and variable shadowing
-        for local in self.current_compiler.locals.iter_mut().rev() {
-            if local.depth != -1 && local.depth < self.current_compiler.scope_depth {
+        for local in self.current_compiler().locals.iter_mut().rev() {
+            if local.depth != -1 && local.depth < scope_depth {
                 break;
             }
 
@@ -536,34 +797,77 @@ impl<'c> Parser<'c> {
     }
 
     fn add_local(&mut self, name: Token) -> () {
-        if self.current_compiler.locals.len() == u8::max_value().into() {
-            self.error("Too many local variables in functino.");
+        if self.current_compiler().locals.len() == u8::MAX.into() {
+            self.error("Too many local variables in function.");
             return;
         }
 
-        self.current_compiler.locals.push(Local { name, depth: -1 });
+        self.current_compiler().locals.push(Local {
+            name,
+            depth: -1,
+            is_captured: false,
+        });
     }
 
-    fn identifier_constant(&mut self, name: &Token) -> u8 {
-        return self.make_constant(Value::Object(ObjString {
-            chars: name.lexeme.clone(),
-        }));
+    fn identifier_constant(&mut self, name: &Token) -> u32 {
+        let string = self.intern(name.lexeme.clone());
+        return self.make_constant(Value::Object(string));
     }
 
-    fn resolve_local(&mut self, compiler: Compiler, name: &Token) -> Option<u8> {
-        for (i, local) in compiler.locals.iter().enumerate().rev() {
+    // Resolve `name` to a stack slot of the compiler at `compiler` in the compiler stack
+    fn resolve_local(&mut self, compiler: usize, name: &Token) -> Option<u8> {
+        let mut found = None;
+        for (i, local) in self.compilers[compiler].locals.iter().enumerate().rev() {
             if name.lexeme == local.name.lexeme {
-                if local.depth == -1 {
-                    self.error("Can't read local variable in its own initializer.");
-                }
-                return Some(
-                    i.try_into()
-                        .expect("Can'nt convert usize into u8 in resolve local"),
-                );
+                found = Some((i, local.depth));
+                break;
             }
         }
 
-        None
+        let (i, depth) = found?;
+        if depth == -1 {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(
+            i.try_into()
+                .expect("Can'nt convert usize into u8 in resolve local"),
+        )
+    }
+
+    // Resolve `name` to a variable captured from the procedures enclosing the compiler
+    // at `compiler`. Every procedure in between captures it as well, so it is passed
+    // down from closure to closure when they are created
+    fn resolve_upvalue(&mut self, compiler: usize, name: &Token) -> Option<u8> {
+        if compiler == 0 {
+            return None;
+        }
+
+        if let Some(local) = self.resolve_local(compiler - 1, name) {
+            self.compilers[compiler - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(compiler, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(compiler - 1, name)?;
+        Some(self.add_upvalue(compiler, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, compiler: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &self.compilers[compiler].function.upvalues;
+
+        // Capturing the same variable twice reuses the upvalue
+        if let Some(i) = upvalues.iter().position(|u| *u == upvalue) {
+            return i as u8;
+        }
+
+        if upvalues.len() == u8::MAX as usize + 1 {
+            self.error("Too many closure variables in brick.");
+            return 0;
+        }
+
+        let upvalues = &mut self.compilers[compiler].function.upvalues;
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
     }
 
     fn synchronize(&mut self) -> () {
@@ -631,16 +935,25 @@ impl<'c> Parser<'c> {
     }
 
     fn begin_scope(&mut self) -> () {
-        self.current_compiler.scope_depth += 1;
+        self.current_compiler().scope_depth += 1;
     }
 
     fn end_scope(&mut self) -> () {
-        self.current_compiler.scope_depth -= 1;
-        for i in (0..self.current_compiler.locals.len()).rev() {
-            if self.current_compiler.locals[i].depth > self.current_compiler.scope_depth {
+        self.current_compiler().scope_depth -= 1;
+        let scope_depth = self.current_compiler().scope_depth;
+
+        while let Some(local) = self.current_compiler().locals.last() {
+            if local.depth <= scope_depth {
+                break;
+            }
+
+            // Captured locals move off the stack into their upvalue
+            if local.is_captured {
+                self.emit_byte(OpCode::OpCloseUpvalue);
+            } else {
                 self.emit_byte(OpCode::OpPop);
-                self.current_compiler.locals.pop();
             }
+            self.current_compiler().locals.pop();
         }
     }
 
@@ -658,16 +971,130 @@ impl<'c> Parser<'c> {
         self.consume(TokenType::TokenRightParen, "Expect ')' after expression.");
     }
 
+    fn call(&mut self, _can_assign: bool) -> () {
+        let arg_count = self.argument_list();
+        self.emit_byte(OpCode::OpCall(arg_count));
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: u8 = 0;
+        if !self.check(TokenType::TokenRightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX {
+                    self.error("Can't have more than 255 arguments.");
+                } else {
+                    arg_count += 1;
+                }
+
+                if !self.match_token(TokenType::TokenComma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::TokenRightParen, "Expected ')' after arguments.");
+        arg_count
+    }
+
+    // List literal, the items are collected from the stack by `OpBuildList`
+    fn list(&mut self, _can_assign: bool) -> () {
+        let mut count: u32 = 0;
+        if !self.check(TokenType::TokenRightBracket) {
+            loop {
+                self.expression();
+                count += 1;
+
+                if !self.match_token(TokenType::TokenComma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(
+            TokenType::TokenRightBracket,
+            "Expected ']' after list items.",
+        );
+        self.emit_byte(OpCode::OpBuildList(count));
+    }
+
+    // Map literal. A `{` starting a statement opens a block, anywhere else
+    // in an expression it opens a map. The keys and values are collected
+    // from the stack by `OpBuildMap`
+    fn map(&mut self, _can_assign: bool) -> () {
+        let mut count: u32 = 0;
+        if !self.check(TokenType::TokenRightBrace) {
+            loop {
+                self.expression();
+                self.consume(TokenType::TokenColon, "Expected ':' after map key.");
+                self.expression();
+                count += 1;
+
+                if !self.match_token(TokenType::TokenComma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(
+            TokenType::TokenRightBrace,
+            "Expected '}' after map entries.",
+        );
+        self.emit_byte(OpCode::OpBuildMap(count));
+    }
+
+    fn index(&mut self, can_assign: bool) -> () {
+        self.expression();
+        self.consume(TokenType::TokenRightBracket, "Expected ']' after index.");
+
+        if can_assign && self.match_token(TokenType::TokenEqual) {
+            self.expression();
+            self.emit_byte(OpCode::OpIndexSet);
+        } else {
+            self.emit_byte(OpCode::OpIndexGet);
+        }
+    }
+
+    fn dot(&mut self, can_assign: bool) -> () {
+        self.consume(
+            TokenType::TokenIdentifier,
+            "Expected property name after '.'.",
+        );
+        let name = self.identifier_constant(&self.tokens[self.current - 1]);
+
+        if can_assign && self.match_token(TokenType::TokenEqual) {
+            self.expression();
+            self.emit_byte(OpCode::OpSetProperty(name));
+        } else if self.match_token(TokenType::TokenLeftParen) {
+            // Calling a method right away skips creating a bound method
+            let arg_count = self.argument_list();
+            self.emit_byte(OpCode::OpInvoke(name, arg_count));
+        } else {
+            self.emit_byte(OpCode::OpGetProperty(name));
+        }
+    }
+
+    fn this_(&mut self, _can_assign: bool) -> () {
+        if self.class_depth == 0 {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        // `this` is resolved like the local living in slot zero
+        self.variable(false);
+    }
+
     fn unary(&mut self, _can_assign: bool) -> () {
         let operator_type = self.tokens[self.current - 1]._type;
 
-        // Compile the operand
-        self.parse_precedence(Precedence::PrecUnary);
+        // Compile the operand. It includes powers, so `-2 ^ 2` is `-(2 ^ 2)`
+        self.parse_precedence(Precedence::PrecPower);
 
         // Emit le operator instuction
         match operator_type {
             TokenType::TokenMinus => self.emit_byte(OpCode::OpNegate),
             TokenType::TokenBang => self.emit_byte(OpCode::OpNot),
+            TokenType::TokenTilde => self.emit_byte(OpCode::OpBitNot),
             _ => return,
         };
     }
@@ -675,7 +1102,12 @@ impl<'c> Parser<'c> {
     fn binary(&mut self, _can_assign: bool) -> () {
         let operator_type = self.tokens[self.current - 1]._type;
         let rule = self.get_rule(operator_type);
-        self.parse_precedence(rule.precedence.next());
+        // `^` is right-associative, so its right operand may be another power
+        if operator_type == TokenType::TokenPow {
+            self.parse_precedence(Precedence::PrecPower);
+        } else {
+            self.parse_precedence(rule.precedence.next());
+        }
 
         match operator_type {
             TokenType::TokenPlus => self.emit_byte(OpCode::OpAdd),
@@ -685,6 +1117,10 @@ impl<'c> Parser<'c> {
             TokenType::TokenPow => self.emit_byte(OpCode::OpPow),
             TokenType::TokenShiftLeft => self.emit_byte(OpCode::OpLeftShift),
             TokenType::TokenShiftRigth => self.emit_byte(OpCode::OpRightShift),
+            TokenType::TokenPercent => self.emit_byte(OpCode::OpModulo),
+            TokenType::TokenAmpersand => self.emit_byte(OpCode::OpBitAnd),
+            TokenType::TokenPipe => self.emit_byte(OpCode::OpBitOr),
+            TokenType::TokenTilde => self.emit_byte(OpCode::OpBitXor),
             TokenType::TokenBangEqual => self.emit_bytes(OpCode::OpEqual, OpCode::OpNot),
             TokenType::TokenEqualEqual => self.emit_byte(OpCode::OpEqual),
             TokenType::TokenGreater => self.emit_byte(OpCode::OpGreater),
@@ -705,9 +1141,8 @@ impl<'c> Parser<'c> {
     }
 
     fn parse_string(&mut self, _can_assign: bool) -> () {
-        self.emit_constant(Value::Object(ObjString {
-            chars: self.tokens[self.current - 1].lexeme.clone(),
-        }));
+        let string = self.intern(self.tokens[self.current - 1].lexeme.clone());
+        self.emit_constant(Value::Object(string));
     }
 
     fn variable(&mut self, can_assign: bool) -> () {
@@ -715,28 +1150,99 @@ impl<'c> Parser<'c> {
     }
 
     fn named_variable(&mut self, name: &Token, can_assign: bool) -> () {
-        let (op_get, op_set) = match self.resolve_local(self.current_compiler.clone(), &name) {
-            None => {
-                let arg = self.identifier_constant(&name);
-                (OpCode::OpGetGlobal(arg), OpCode::OpSetGlobal(arg))
-            }
-            Some(arg) => (OpCode::OpGetLocal(arg as u8), OpCode::OpSetLocal(arg as u8)),
-        };
+        let (op_get, op_set) = self.variable_ops(name);
 
         if self.match_token(TokenType::TokenEqual) && can_assign {
             self.expression();
             self.emit_byte(op_set);
+        } else if let Some(operator) = self.match_compound_assignment(can_assign) {
+            // The variable is read once, combined with the value and written back
+            self.emit_byte(op_get);
+            self.expression();
+            self.emit_byte(operator);
+            self.emit_byte(op_set);
+        } else if self.match_token(TokenType::TokenPlusPlus)
+            || self.match_token(TokenType::TokenMinusMinus)
+        {
+            // Postfix form, the old value is the result
+            let operator = self.tokens[self.current - 1]._type;
+            self.emit_byte(op_get);
+            self.emit_increment(op_get, op_set, operator);
+            self.emit_byte(OpCode::OpPop);
         } else {
             self.emit_byte(op_get);
         }
     }
 
+    // Consume a compound assignment operator like `+=` if assigning is
+    // allowed, and return the instruction combining the two values
+    fn match_compound_assignment(&mut self, can_assign: bool) -> Option<OpCode> {
+        if !can_assign {
+            return None;
+        }
+
+        let operator = match self.tokens[self.current]._type {
+            TokenType::TokenPlusEqual => OpCode::OpAdd,
+            TokenType::TokenMinusEqual => OpCode::OpSubtract,
+            TokenType::TokenStarEqual => OpCode::OpMultiply,
+            TokenType::TokenSlashEqual => OpCode::OpDivide,
+            TokenType::TokenPowEqual => OpCode::OpPow,
+            _ => return None,
+        };
+        self.advance();
+        Some(operator)
+    }
+
+    // Prefix `++a` and `--a`, the new value is the result
+    fn prefix_increment(&mut self, _can_assign: bool) -> () {
+        let operator = self.tokens[self.current - 1]._type;
+        if !self.match_token(TokenType::TokenIdentifier) {
+            self.error_at_current("Invalid increment target.");
+            return;
+        }
+
+        let (op_get, op_set) = self.variable_ops(&self.tokens[self.current - 1]);
+        self.emit_increment(op_get, op_set, operator);
+    }
+
+    // `++` or `--` following something that isn't a variable
+    fn invalid_increment(&mut self, _can_assign: bool) -> () {
+        self.error("Invalid increment target.");
+    }
+
+    // Add or subtract one from a variable, leaving its new value on the stack
+    fn emit_increment(&mut self, op_get: OpCode, op_set: OpCode, operator: TokenType) -> () {
+        self.emit_byte(op_get);
+        self.emit_constant(Value::Int(1));
+        if operator == TokenType::TokenPlusPlus {
+            self.emit_byte(OpCode::OpAdd);
+        } else {
+            self.emit_byte(OpCode::OpSubtract);
+        }
+        self.emit_byte(op_set);
+    }
+
+    // Instructions reading and assigning the variable `name`
+    fn variable_ops(&mut self, name: &Token) -> (OpCode, OpCode) {
+        let compiler = self.compilers.len() - 1;
+        if let Some(arg) = self.resolve_local(compiler, name) {
+            (OpCode::OpGetLocal(arg), OpCode::OpSetLocal(arg))
+        } else if let Some(arg) = self.resolve_upvalue(compiler, name) {
+            (OpCode::OpGetUpvalue(arg), OpCode::OpSetUpvalue(arg))
+        } else {
+            let arg = self.identifier_constant(name);
+            match u8::try_from(arg) {
+                Ok(arg) => (OpCode::OpGetGlobal(arg), OpCode::OpSetGlobal(arg)),
+                Err(_) => (OpCode::OpGetGlobalLong(arg), OpCode::OpSetGlobalLong(arg)),
+            }
+        }
+    }
+
     fn parse_precedence(&mut self, precedence: Precedence) -> () {
         self.advance();
         let prefix_rule = match self.get_rule(self.tokens[self.current - 1]._type).prefix {
             Some(rule) => rule,
             None => {
-                println!("On type: {}", self.tokens[self.current - 1]._type);
                 self.error("Expected expression");
                 return;
             }
@@ -754,7 +1260,10 @@ impl<'c> Parser<'c> {
             infix_rule(self, can_assign);
         }
 
-        if can_assign && self.match_token(TokenType::TokenEqual) {
+        if can_assign
+            && (self.match_token(TokenType::TokenEqual)
+                || self.match_compound_assignment(can_assign).is_some())
+        {
             self.error("Invalid assignment target.");
         }
     }
@@ -768,18 +1277,27 @@ impl<'c> Parser<'c> {
         precedence <= current_precedence
     }
 
+    // Literals with a fractional part are floats, all others integers
     fn parse_number(&mut self, _can_assign: bool) -> () {
-        let value = match self.tokens[self.current - 1].lexeme.parse() {
-            Ok(v) => v,
-            Err(_) => 0.0, //TODO proper error handling
+        let lexeme = &self.tokens[self.current - 1].lexeme;
+        let value = if lexeme.contains('.') {
+            Value::Number(lexeme.parse().unwrap_or(0.0))
+        } else {
+            match lexeme.parse() {
+                Ok(value) => Value::Int(value),
+                Err(_) => {
+                    self.error("Integer literal is too large.");
+                    Value::Int(0)
+                }
+            }
         };
 
-        self.emit_constant(Value::Number(value));
+        self.emit_constant(value);
     }
 
     fn or_(&mut self, _can_assign: bool) -> () {
-        let else_jump = self.emit_jump(OpCode::OpJumpIfFalse(0xff)) as usize;
-        let end_jump = self.emit_jump(OpCode::OpJump(0xff)) as usize;
+        let else_jump = self.emit_byte(OpCode::OpJumpIfFalse(0xffff));
+        let end_jump = self.emit_byte(OpCode::OpJump(0xffff));
 
         self.patch_jump(else_jump);
         self.emit_byte(OpCode::OpPop);
@@ -788,20 +1306,47 @@ impl<'c> Parser<'c> {
         self.patch_jump(end_jump);
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.chunk.add_constant(value);
-        match u8::try_from(constant) {
-            Ok(index) => index,
-            Err(_) => {
-                self.error("Too many constants in one chunk");
-                0
-            }
+    // Allocate an object on the VM heap. The constants of the procedures still being
+    // compiled aren't reachable from the VM, so they are passed along as roots
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let roots = self
+            .compilers
+            .iter()
+            .flat_map(|compiler| compiler.function.chunk.constants.array.iter().copied());
+        return self.vm.alloc_with_roots(obj, roots);
+    }
+
+    // Get the interned string object for `chars`, see `alloc`
+    fn intern(&mut self, chars: String) -> ObjRef {
+        let roots = self
+            .compilers
+            .iter()
+            .flat_map(|compiler| compiler.function.chunk.constants.array.iter().copied());
+        return self.vm.intern_with_roots(chars, roots);
+    }
+
+    fn make_constant(&mut self, value: Value) -> u32 {
+        let constant = self.current_chunk().add_constant(value);
+        if constant >= CONSTANTS_MAX {
+            self.error("Too many constants in one chunk");
+            return 0;
         }
+        return constant as u32;
+    }
+
+    // Compiler of the innermost procedure being compiled
+    fn current_compiler(&mut self) -> &mut Compiler {
+        self.compilers.last_mut().expect("No active compiler")
+    }
+
+    // Chunk of the function currently being compiled
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.current_compiler().function.chunk
     }
 
     fn emit_byte(&mut self, byte: OpCode) -> usize {
-        self.chunk
-            .write_chunk(byte, self.tokens[self.current - 1].line)
+        let line = self.tokens[self.current - 1].line;
+        self.current_chunk().write_chunk(byte, line)
     }
 
     fn emit_bytes(&mut self, byte1: OpCode, byte2: OpCode) -> usize {
@@ -810,7 +1355,7 @@ impl<'c> Parser<'c> {
     }
 
     fn emit_loop(&mut self, loop_start: usize) -> () {
-        let offset = self.chunk.code.len() - loop_start;
+        let offset = self.current_chunk().code.len() - loop_start;
         let offset = match u16::try_from(offset) {
             Ok(v) => v,
             Err(_) => {
@@ -822,15 +1367,8 @@ impl<'c> Parser<'c> {
         self.emit_byte(OpCode::OpLoop(offset));
     }
 
-    fn emit_jump(&mut self, instruction: OpCode) -> isize {
-        self.emit_byte(instruction);
-        self.emit_byte(OpCode::OpJumpIfFalse(0xff)); //TODO
-        self.emit_byte(OpCode::OpJumpIfFalse(0xff));
-        return (self.chunk.code.len() - 2) as isize;
-    }
-
     fn patch_jump(&mut self, offset: usize) -> () {
-        let jump = self.chunk.code.len() - 1 - offset;
+        let jump = self.current_chunk().code.len() - 1 - offset;
 
         let jump = match u16::try_from(jump) {
             Ok(jump) => jump,
@@ -840,7 +1378,7 @@ impl<'c> Parser<'c> {
             }
         };
 
-        match self.chunk.code[offset] {
+        match self.current_chunk().code[offset] {
             OpCode::OpJumpIfFalse(ref mut o) => *o = jump,
             OpCode::OpJump(ref mut o) => *o = jump,
             _ => panic!("instruction at position is not jump"),
@@ -849,18 +1387,38 @@ impl<'c> Parser<'c> {
 
     fn emit_constant(&mut self, value: Value) -> () {
         let index = self.make_constant(value);
-        self.emit_byte(OpCode::OpConstant(index));
+        self.emit_constant_op(index, OpCode::OpConstant, OpCode::OpConstantLong);
+    }
+
+    // Emit the short form of an instruction taking a constant
+    // index if the index fits into a byte, its long form otherwise
+    fn emit_constant_op(
+        &mut self,
+        index: u32,
+        short: fn(u8) -> OpCode,
+        long: fn(u32) -> OpCode,
+    ) -> () {
+        match u8::try_from(index) {
+            Ok(index) => self.emit_byte(short(index)),
+            Err(_) => self.emit_byte(long(index)),
+        };
     }
 
+    // Procedures without an explicit `goodbye` return money,
+    // initializers return the new instance
     fn emit_return(&mut self) -> () {
-        self.emit_byte(OpCode::OpReturn);
+        if self.current_compiler().function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::OpGetLocal(0), OpCode::OpReturn);
+        } else {
+            self.emit_bytes(OpCode::OpNil, OpCode::OpReturn);
+        }
     }
 
-    fn start_loop(&self) -> usize {
-        self.chunk.code.len()
+    fn start_loop(&mut self) -> usize {
+        self.current_chunk().code.len()
     }
 
-    /// Reports an error at the current token, printing a message and entering panic mode.
+    /// Reports an error at the current token and enters panic mode.
     /// Ensures that multiple errors don't cascade.
     fn error_at_current(&mut self, msg: &'c str) -> () {
         self.error_at(msg, self.current);
@@ -870,7 +1428,7 @@ impl<'c> Parser<'c> {
         self.error_at(msg, self.current - 1);
     }
 
-    /// Reports an error at a specific token index and switches the parser into panic mode.
+    /// Records an error at a specific token index and switches the parser into panic mode.
     /// This allows the parser to recover and continue parsing after an error is encountered.
     fn error_at(&mut self, msg: &'c str, index: usize) -> () {
         if self.panic_mode {
@@ -879,15 +1437,18 @@ impl<'c> Parser<'c> {
         self.panic_mode = true;
 
         let token = &self.tokens[index];
-        print!("[line {}:{}] Error", token.line, token.col);
-
-        match token._type {
-            TokenType::TokenEOF => print!(" at end"),
-            TokenType::TokenError => print!(""),
-            _ => print!(" at '{}'", token.lexeme),
+        // Error tokens carry the lexer's message instead of source text
+        let lexeme = match token._type {
+            TokenType::TokenEOF => None,
+            TokenType::TokenError => Some(String::new()),
+            _ => Some(token.lexeme.clone()),
         };
 
-        println!(": {}", msg);
-        self.had_error = true;
+        self.errors.push(CompileError {
+            line: token.line,
+            column: token.col,
+            lexeme,
+            message: msg.to_string(),
+        });
     }
 }
@@ -0,0 +1,175 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+//
+// This file contains the interactive interpreter. Entries are read until
+// their brackets are balanced, so blocks can span several lines, and the
+// value of a final expression statement is printed.
+
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::IsTerminal;
+
+use pf::lexer::{Scanner, TokenType, KEYWORDS};
+use pf::memory::Heap;
+use pf::value::Value;
+use pf::vm::VM;
+
+use crate::line_editor::LineEditor;
+
+const HELP: &str = "Enter Vera code to run it, blocks may span several lines.
+The value of an expression statement is printed.
+
+:help           Show this message
+:reset          Forget every global variable
+:load <path>    Run a Vera file in this session
+:disasm         Show the byte code of the previous entry
+:disasm <code>  Show the byte code of <code>";
+
+/// Interactive session, globals persist from one entry to the next.
+pub struct Repl {
+    vm: VM,
+    editor: Option<LineEditor>, // Line editor, only used when reading from a terminal
+    last_entry: String,         // Most recent code entered, shown by `:disasm`
+}
+
+impl Repl {
+    pub fn new(heap: Heap, trace: bool) -> Repl {
+        let mut vm = VM::with_heap(heap);
+        vm.trace |= trace;
+        let editor = if io::stdin().is_terminal() {
+            Some(LineEditor::new())
+        } else {
+            None
+        };
+        Repl {
+            vm,
+            editor,
+            last_entry: String::new(),
+        }
+    }
+
+    // Read and run entries until the input ends
+    pub fn run(&mut self) -> () {
+        let mut entry = String::new();
+        loop {
+            let prompt = if entry.is_empty() { "> " } else { "... " };
+            let line = self.read_line(prompt);
+            if line.is_empty() {
+                // Run what was typed before the input ended
+                if !entry.trim().is_empty() {
+                    self.evaluate(&entry);
+                }
+                break;
+            }
+
+            if entry.is_empty() && line.trim_start().starts_with(':') {
+                self.command(line.trim());
+                continue;
+            }
+
+            entry.push_str(&line);
+            if is_incomplete(&entry) {
+                continue;
+            }
+            if !entry.trim().is_empty() {
+                self.evaluate(&entry);
+            }
+            entry.clear();
+        }
+    }
+
+    // Read the next line including its newline, an empty string once the input ends
+    fn read_line(&mut self, prompt: &str) -> String {
+        if let Some(editor) = &mut self.editor {
+            let vm = &self.vm;
+            match editor.read_line(prompt, |prefix| completions(vm, prefix)) {
+                Ok(Some(line)) => return line + "\n",
+                Ok(None) => return String::new(),
+                // Without a usable terminal, lines are read as they are
+                Err(_) => self.editor = None,
+            }
+        }
+
+        print!("{}", prompt);
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        io::stdin()
+            .read_line(&mut line)
+            .expect("Unable to read line from the REPL");
+        line
+    }
+
+    // Run an entry and print its value
+    fn evaluate(&mut self, entry: &str) -> () {
+        self.last_entry = entry.to_string();
+
+        // The VM resets itself after an error, so the session goes on
+        match self.vm.evaluate(entry) {
+            Ok(Value::Nil) => (),
+            Ok(value) => println!("{}", self.vm.format_value(value)),
+            Err(error) => eprintln!("{}", error),
+        }
+    }
+
+    // Run a `:` meta-command
+    fn command(&mut self, line: &str) -> () {
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+
+        match (command, argument) {
+            (":help", "") => println!("{}", HELP),
+            (":reset", "") => self.vm.reset(),
+            (":load", path) if !path.is_empty() => match fs::read_to_string(path) {
+                Ok(code) => {
+                    if let Err(error) = self.vm.interpret(&code) {
+                        eprintln!("{}", error);
+                    }
+                }
+                Err(error) => eprintln!("Unable to read file {}: {}", path, error),
+            },
+            (":disasm", code) => {
+                let code = if code.is_empty() {
+                    self.last_entry.clone()
+                } else {
+                    code.to_string()
+                };
+                if let Err(error) = self.vm.disassemble(&code) {
+                    eprintln!("{}", error);
+                }
+            }
+            _ => eprintln!("Unknown command '{}', try :help", line),
+        }
+    }
+}
+
+// Keywords and global variables starting with `prefix`
+fn completions(vm: &VM, prefix: &str) -> Vec<String> {
+    let keywords = KEYWORDS.iter().map(|(keyword, _)| keyword.to_string());
+    keywords
+        .chain(vm.global_names())
+        .filter(|word| word.starts_with(prefix))
+        .collect()
+}
+
+// Whether `source` has more opening than closing brackets,
+// so the entry continues on the next line
+fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth: isize = 0;
+    for token in scanner.scan_tokens().iter() {
+        match token._type {
+            TokenType::TokenLeftParen | TokenType::TokenLeftBrace | TokenType::TokenLeftBracket => {
+                depth += 1
+            }
+            TokenType::TokenRightParen
+            | TokenType::TokenRightBrace
+            | TokenType::TokenRightBracket => depth -= 1,
+            _ => (),
+        }
+    }
+    depth > 0
+}
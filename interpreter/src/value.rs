@@ -2,24 +2,24 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file
 
-use crate::object::ObjString;
+use crate::memory::{Heap, ObjRef};
+use crate::vm::VM;
 
-#[derive(Clone, PartialEq)]
+// Vera values are small and copied freely, everything
+// larger lives on the heap behind an `ObjRef`. Integer literals are
+// `Int`s, literals with a fractional part are `Number`s
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Value {
     Bool(bool),
     Number(f64),
-    Object(ObjString),
+    Int(i64),
+    Object(ObjRef),
     Nil,
 }
 
 impl Value {
-    pub fn print_value(self) -> () {
-        match self {
-            Value::Bool(v) => println!("{}", v),
-            Value::Number(v) => println!("{}", v),
-            Value::Nil => println!("Nil"),
-            Value::Object(v) => println!("{}", v),
-        }
+    pub fn print_value(self, heap: &Heap) -> () {
+        println!("{}", heap.format_value(self));
     }
 
     pub fn is_falsey(&self) -> bool {
@@ -29,6 +29,28 @@ impl Value {
             _ => false,
         }
     }
+
+    /// The value as a float, integers are promoted. `None` for non numbers
+    pub fn as_float(self) -> Option<f64> {
+        match self {
+            Value::Number(v) => Some(v),
+            Value::Int(v) => Some(v as f64),
+            _ => None,
+        }
+    }
+
+    /// The value as an integer, floats without a fractional part in the
+    /// range of `i64` are converted. `None` for everything else
+    pub fn as_int(self) -> Option<i64> {
+        match self {
+            Value::Int(v) => Some(v),
+            // `i64::MAX as f64` rounds up to 2^63, which is out of range
+            Value::Number(v) if v.fract() == 0.0 && v >= i64::MIN as f64 && v < i64::MAX as f64 => {
+                Some(v as i64)
+            }
+            _ => None,
+        }
+    }
 }
 
 pub struct ValueArray {
@@ -51,3 +73,166 @@ impl ValueArray {
         self.array.clear();
     }
 }
+
+// Conversions of values that don't live on the heap
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => value.into(),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = String;
+
+    // Integers are promoted
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value.as_float() {
+            Some(v) => Ok(v),
+            None => Err("Value is not a number.".to_string()),
+        }
+    }
+}
+
+impl TryFrom<Value> for i64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Int(v) => Ok(v),
+            _ => Err("Value is not an integer.".to_string()),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Bool(v) => Ok(v),
+            _ => Err("Value is not a bool.".to_string()),
+        }
+    }
+}
+
+// Conversion of a Rust value into a Vera value. Strings are allocated
+// on the VM heap, so unlike `From` the conversion needs the VM
+pub trait ToValue {
+    fn to_value(&self, vm: &mut VM) -> Value;
+}
+
+// Conversion of a Vera value into a Rust value, failing with
+// a message if the value has a different type
+pub trait FromValue: Sized {
+    fn from_value(value: Value, vm: &VM) -> Result<Self, String>;
+}
+
+impl ToValue for Value {
+    fn to_value(&self, _vm: &mut VM) -> Value {
+        *self
+    }
+}
+
+impl ToValue for f64 {
+    fn to_value(&self, _vm: &mut VM) -> Value {
+        Value::from(*self)
+    }
+}
+
+impl ToValue for i64 {
+    fn to_value(&self, _vm: &mut VM) -> Value {
+        Value::from(*self)
+    }
+}
+
+impl ToValue for bool {
+    fn to_value(&self, _vm: &mut VM) -> Value {
+        Value::from(*self)
+    }
+}
+
+impl ToValue for &str {
+    fn to_value(&self, vm: &mut VM) -> Value {
+        Value::Object(vm.intern(self.to_string()))
+    }
+}
+
+impl ToValue for String {
+    fn to_value(&self, vm: &mut VM) -> Value {
+        Value::Object(vm.intern(self.clone()))
+    }
+}
+
+impl<T: ToValue> ToValue for Option<T> {
+    fn to_value(&self, vm: &mut VM) -> Value {
+        match self {
+            Some(value) => value.to_value(vm),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value, _vm: &VM) -> Result<Self, String> {
+        Ok(value)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: Value, _vm: &VM) -> Result<Self, String> {
+        f64::try_from(value)
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: Value, _vm: &VM) -> Result<Self, String> {
+        i64::try_from(value)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value, _vm: &VM) -> Result<Self, String> {
+        bool::try_from(value)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value, vm: &VM) -> Result<Self, String> {
+        match vm.as_str(value) {
+            Some(chars) => Ok(chars.to_string()),
+            None => Err("Value is not a string.".to_string()),
+        }
+    }
+}
+
+// `money` converts to `None`
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value, vm: &VM) -> Result<Self, String> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::from_value(value, vm).map(Some),
+        }
+    }
+}
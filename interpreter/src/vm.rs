@@ -2,244 +2,376 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file.
 
-// Enum representing the possible outcomes of interpreting bytecode.
-pub enum InterpretResult {
-    InterpretOk,            // Execution completed successfully.
-    InterpretCompileError,  // There was an error during the compilation phase.
-    InterpretRuneTimeError, // An error occurred during execution.
-}
-
-use std::any::Any;
 use std::collections::HashMap;
 
+use crate::builtins;
+use crate::bytecode;
 use crate::chunk::Chunk;
 use crate::chunk::OpCode;
-use crate::object::ObjString;
+use crate::error::{RuntimeError, TraceFrame, VeraError};
+use crate::memory::{Heap, ObjRef};
+use crate::object::{
+    MapKey, NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjList, ObjMap,
+    ObjNative, ObjString, ObjUpvalue, INITIALIZER, UNHASHABLE_KEY,
+};
 use crate::parser::Parser;
 
+// Maximum depth of nested procedure calls
+const FRAMES_MAX: usize = 64;
+
+// Error for integer arithmetic whose result doesn't fit an `i64`
+const INTEGER_OVERFLOW: &str = "Integer overflow.";
+
+/// A single ongoing procedure call.
+pub struct CallFrame {
+    closure: ObjRef,  // `ObjClosure` being executed
+    function: ObjRef, // `ObjFunction` of the closure, whose chunk is executed
+    ip: usize,        // Index of the next instruction in the procedure's chunk
+    slots: usize,     // Index of the frame's first value stack slot
+}
+
 /// Virtual Machine (VM) for executing Vera bytecode in a stack-based architecture.
+/// Globals, including native functions, persist across calls to `interpret`.
 pub struct VM {
-    pub chunk: Chunk,      // Byte code chunk
-    pub code: Vec<OpCode>, // VM instructions
-    pub stack: Vec<Value>, // VM value stack
-    pub globals: HashMap<String, Value>,
-    pub ip: usize,
+    frames: Vec<CallFrame>,          // Stack of active procedure calls
+    stack: Vec<Value>,               // VM value stack
+    globals: HashMap<ObjRef, Value>, // Global variables keyed by their interned name
+    open_upvalues: Vec<ObjRef>,      // `ObjUpvalue`s of captured variables still on the stack
+    heap: Heap,                      // Every object created by the compiler and the VM
+    pub trace: bool,                 // Print the stack and every instruction before executing it
 }
 
 use crate::lexer::Scanner;
-use crate::value::Value;
+use crate::value::{FromValue, ToValue, Value};
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl VM {
-    /// Executes bytecode instructions stored in the `chunk`.
+    /// Creates a VM with a default configured heap.
+    pub fn new() -> VM {
+        VM::with_heap(Heap::new())
+    }
+
+    /// Creates a VM allocating its objects on `heap`, for example
+    /// one with a custom garbage collection threshold.
+    pub fn with_heap(heap: Heap) -> VM {
+        let mut vm = VM {
+            frames: Vec::new(),
+            stack: Vec::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            heap,
+            trace: cfg!(feature = "trace-execution"),
+        };
+        builtins::define_builtins(&mut vm);
+        vm
+    }
+
+    /// Makes the Rust function `function` callable from Vera code as the global `name`.
+    /// Calling it with other than `arity` arguments is a runtime error.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) -> () {
+        // Keep the name on the stack while the native is allocated
+        let global = self.intern(name.to_string());
+        self.push(Value::Object(global));
+
+        let native = self.alloc(Obj::Native(ObjNative {
+            name: name.to_string(),
+            arity,
+            function,
+        }));
+        self.globals.insert(global, Value::Object(native));
+        self.pop();
+    }
+
+    /// Executes bytecode instructions of the procedure in the topmost call frame.
     ///
     /// The function loops over each instruction, processes it,
     /// and handles various opcodes such as mathematical operations,
-    /// stack manipulations, and conditional operations.
+    /// stack manipulations, conditional operations and procedure calls.
     ///
-    /// Returns `InterpretResult` indicating the result of execution.
-    fn run(&mut self) -> InterpretResult {
+    /// Runs until the procedure in the topmost call frame returns and only `base`
+    /// frames are left. Returns the value it returned, or the `RuntimeError`
+    /// that stopped execution.
+    fn run(&mut self, base: usize) -> Result<Value, RuntimeError> {
         // Loop over all instruction inside the byte code chunk
         // and execute them
         loop {
-            match self.code[self.ip] {
+            // Fetch the instruction and move on to the next one, so
+            // the frame already points past it when a call is made
+            let frame = self.frame_mut();
+            let (function, ip) = (frame.function, frame.ip);
+            frame.ip += 1;
+            let instruction = self.heap.as_function(function).chunk.code[ip];
+
+            if self.trace {
+                self.trace_instruction(function, ip);
+            }
+
+            match instruction {
                 OpCode::OpReturn => {
-                    return InterpretResult::InterpretOk;
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("Couldn't pop call frame");
+
+                    // Discard the callee and its arguments and locals,
+                    // moving the captured ones into their upvalues
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+
+                    // Returning from the procedure `run` was started for ends execution
+                    if self.frames.len() == base {
+                        return Ok(result);
+                    }
+                    self.push(result);
+                }
+                OpCode::OpCall(arg_count) => {
+                    let callee = self.peek(arg_count as usize);
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::OpClass(i) => {
+                    let name = self.read_string(i as usize);
+                    let name = self.heap.as_string(name).chars.clone();
+                    let class = self.alloc(Obj::Class(ObjClass::new(name)));
+                    self.push(Value::Object(class));
+                }
+                OpCode::OpMethod(i) => {
+                    // The method sits on top of the class it belongs to. Loaded
+                    // byte code isn't guaranteed to put them there
+                    let name = self.read_string(i as usize);
+                    let class = match self.peek(1) {
+                        Value::Object(class) if matches!(self.heap.get(class), Obj::Class(_)) => {
+                            class
+                        }
+                        _ => {
+                            return Err(self.runtime_error("Methods can only be added to classes."))
+                        }
+                    };
+                    let method = match self.peek(0) {
+                        Value::Object(method)
+                            if matches!(self.heap.get(method), Obj::Closure(_)) =>
+                        {
+                            method
+                        }
+                        _ => return Err(self.runtime_error("Methods must be bricks.")),
+                    };
+                    self.heap.as_class_mut(class).methods.insert(name, method);
+                    self.pop();
+                }
+                OpCode::OpGetProperty(i) => {
+                    let instance = match self.as_instance(self.peek(0)) {
+                        Some(instance) => instance,
+                        None => {
+                            return Err(self.runtime_error("Only instances have properties."));
+                        }
+                    };
+                    let name = self.read_string(i as usize);
+
+                    // Fields shadow methods of the same name
+                    let instance = self.heap.as_instance(instance);
+                    if let Some(&value) = instance.fields.get(&name) {
+                        self.pop();
+                        self.push(value);
+                    } else {
+                        self.bind_method(instance.class, name)?;
+                    }
+                }
+                OpCode::OpSetProperty(i) => {
+                    let instance = match self.as_instance(self.peek(1)) {
+                        Some(instance) => instance,
+                        None => {
+                            return Err(self.runtime_error("Only instances have fields."));
+                        }
+                    };
+                    let name = self.read_string(i as usize);
+
+                    // Leave the assigned value on the stack in place of the instance
+                    let value = self.pop();
+                    self.heap
+                        .as_instance_mut(instance)
+                        .fields
+                        .insert(name, value);
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::OpInvoke(i, arg_count) => {
+                    let name = self.read_string(i as usize);
+                    self.invoke(name, arg_count)?;
+                }
+                OpCode::OpClosure(index) => {
+                    let function = match self.chunk().read_constant(index as usize) {
+                        Value::Object(function) => function,
+                        _ => panic!("Constant is not a function!"),
+                    };
+
+                    // Capture the enclosing frame's locals or pass on its upvalues. The
+                    // new upvalues stay reachable through `open_upvalues` meanwhile
+                    let slots = self.frame().slots;
+                    let descriptors = self.heap.as_function(function).upvalues.clone();
+                    let mut upvalues = Vec::with_capacity(descriptors.len());
+                    for upvalue in descriptors {
+                        let captured = if upvalue.is_local {
+                            self.capture_upvalue(slots + upvalue.index as usize)
+                        } else {
+                            self.frame_upvalue(upvalue.index)
+                        };
+                        upvalues.push(captured);
+                    }
+
+                    let closure = self.alloc(Obj::Closure(ObjClosure { function, upvalues }));
+                    self.push(Value::Object(closure));
+                }
+                OpCode::OpGetUpvalue(i) => {
+                    let upvalue = self.frame_upvalue(i);
+                    let value = match *self.heap.as_upvalue(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack[slot],
+                        ObjUpvalue::Closed(value) => value,
+                    };
+                    self.push(value);
+                }
+                OpCode::OpSetUpvalue(i) => {
+                    let upvalue = self.frame_upvalue(i);
+                    let value = self.peek(0);
+                    match self.heap.as_upvalue_mut(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack[*slot] = value,
+                        ObjUpvalue::Closed(closed) => *closed = value,
+                    };
+                }
+                OpCode::OpCloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::OpBuildList(count) => {
+                    // The items stay on the stack while the list is allocated
+                    let start = self.stack.len() - count as usize;
+                    let items = self.stack[start..].to_vec();
+                    let list = self.alloc(Obj::List(ObjList { items }));
+                    self.stack.truncate(start);
+                    self.push(Value::Object(list));
+                }
+                OpCode::OpBuildMap(count) => {
+                    // Keys and values alternate on the stack, where
+                    // they stay while the map is allocated
+                    let start = self.stack.len() - 2 * count as usize;
+                    let mut map = ObjMap::new();
+                    for slot in (start..self.stack.len()).step_by(2) {
+                        let (key, value) = (self.stack[slot], self.stack[slot + 1]);
+                        let hashed = self.require_key(key)?;
+                        map.insert(hashed, key, value);
+                    }
+                    let map = self.alloc(Obj::Map(map));
+                    self.stack.truncate(start);
+                    self.push(Value::Object(map));
+                }
+                OpCode::OpIndexGet => {
+                    let value = self.index_get(self.peek(1), self.peek(0))?;
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::OpIndexSet => {
+                    // The assigned value is the result of the assignment
+                    self.index_set(self.peek(2), self.peek(1), self.peek(0))?;
+                    let value = self.pop();
+                    self.pop();
+                    self.pop();
+                    self.push(value);
                 }
                 OpCode::OpConstant(index) => {
                     // Get the value specified by the given index
                     // from the byte code chunk and push it onto the stack
-                    let value = self.chunk.read_constant(index);
+                    let value = self.chunk().read_constant(index as usize);
+                    self.push(value);
+                }
+                OpCode::OpConstantLong(index) => {
+                    let value = self.chunk().read_constant(index as usize);
                     self.push(value);
                 }
                 OpCode::OpNegate => {
                     // Get first value from stack
                     // Check if its a number
-                    // negate it by 1
+                    // negate it
                     // Push it back onto the stack
-                    let mut value = match self.pop() {
-                        Value::Number(v) => v,
+                    let value = match self.pop() {
+                        Value::Int(v) => match v.checked_neg() {
+                            Some(v) => Value::Int(v),
+                            None => return Err(self.runtime_error(INTEGER_OVERFLOW)),
+                        },
+                        Value::Number(v) => Value::Number(-v),
                         _ => {
-                            self.runtime_error("Operand must be a number.");
-                            return InterpretResult::InterpretRuneTimeError;
+                            return Err(self.runtime_error("Operand must be a number."));
                         }
                     };
-
-                    value = -value;
-                    self.push(Value::Number(value));
+                    self.push(value);
                 }
                 OpCode::OpAdd => {
-                    // Get first two values
-                    let (b, a) = (self.pop(), self.pop());
                     // Pattern match the String and number types while ensuring
                     // both a and b are the same type
-                    match (&a, &b) {
-                        (Value::Number(a), Value::Number(b)) => {
-                            self.push(Value::Number(a + b));
-                        }
-
-                        (Value::Object(a), Value::Object(b)) => {
+                    match (self.peek(1), self.peek(0)) {
+                        (Value::Object(a), Value::Object(b))
+                            if self.is_string(a) && self.is_string(b) =>
+                        {
                             // Concatenate both strings
                             // and push the result onto the stack
-                            let result = format!("{}{}", a, b);
-                            self.push(Value::Object(ObjString { chars: result }));
-                        }
-
-                        _ => {
-                            /* Consider adding the values
-                            back onto the stack again
-                            on failure TODO
-
-                            self.push(a);
-                            self.push(b);
-                            */
-                            self.runtime_error("Operand must be a Number or String.");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    }
-                }
-                OpCode::OpSubtract => {
-                    // Get first 2 values from stack
-                    // substract them
-                    // Push them back onto the stack
-                    let value_a = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let value_b = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
+                            let chars =
+                                format!("{}{}", self.heap.as_string(a), self.heap.as_string(b));
+                            let result = self.intern(chars);
+                            self.pop();
+                            self.pop();
+                            self.push(Value::Object(result));
                         }
-                    };
-                    let sub = value_a - value_b;
-                    self.push(Value::Number(sub));
-                }
-                OpCode::OpMultiply => {
-                    // Get first 2 values from stack
-                    // multiply them
-                    // Push them back onto the stack
-                    let value_a = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
+                        (a, b) if a.as_float().is_some() && b.as_float().is_some() => {
+                            self.arithmetic(i64::checked_add, |a, b| a + b)?;
                         }
-                    };
-                    let value_b = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
                         _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
+                            return Err(self.runtime_error("Operand must be a Number or String."));
                         }
-                    };
-                    let mult = value_a * value_b;
-                    self.push(Value::Number(mult));
+                    }
                 }
+                OpCode::OpSubtract => self.arithmetic(i64::checked_sub, |a, b| a - b)?,
+                OpCode::OpMultiply => self.arithmetic(i64::checked_mul, |a, b| a * b)?,
                 OpCode::OpDivide => {
-                    // Get first 2 values from stack
-                    // Devide them
-                    // Push them back onto the stack
-                    let value_a = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let value_b = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let div = value_a / value_b;
-                    self.push(Value::Number(div));
+                    // Division always results in a float
+                    let (b, a) = (self.pop(), self.pop());
+                    match (a.as_float(), b.as_float()) {
+                        (Some(a), Some(b)) => self.push(Value::Number(a / b)),
+                        _ => return Err(self.runtime_error("Operand must be a number")),
+                    }
                 }
                 OpCode::OpLeftShift => {
-                    //TODO ansure the numbers can be downcast to integer
-                    //without loss of information
-
-                    // Get first 2 values from stack
-                    // bit shift them
-                    // Push them back onto the stack
-                    let value_a = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let value_b = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let shift = (value_a as isize) << (value_b as isize);
-                    self.push(Value::Number(shift as f64));
+                    // Bits shifted out of the integer are an overflow
+                    self.shift(|a, b| a.checked_shl(b).filter(|shifted| shifted >> b == a))?
                 }
-                OpCode::OpRightShift => {
-                    //TODO ansure the numbers can be downcast to integer
-                    //without loss of information
-
-                    // Get first 2 values from stack
-                    // bit shift them
-                    // Push them back onto the stack
-                    let value_a = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let value_b = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let shift = (value_a as isize) >> (value_b as isize);
-                    self.push(Value::Number(shift as f64));
+                OpCode::OpRightShift => self.shift(|a, b| a.checked_shr(b))?,
+                OpCode::OpModulo => {
+                    if let (Value::Int(_), Value::Int(0)) = (self.peek(1), self.peek(0)) {
+                        return Err(self.runtime_error("Modulo by zero."));
+                    }
+                    self.arithmetic(int_modulo, float_modulo)?
                 }
+                OpCode::OpBitAnd => self.bitwise(|a, b| a & b)?,
+                OpCode::OpBitOr => self.bitwise(|a, b| a | b)?,
+                OpCode::OpBitXor => self.bitwise(|a, b| a ^ b)?,
+                OpCode::OpBitNot => match self.pop().as_int() {
+                    Some(value) => self.push(Value::Int(!value)),
+                    None => return Err(self.runtime_error("Operand must be an integer.")),
+                },
                 OpCode::OpPow => {
-                    // Get first 2 values from stack
-                    // calculate the one to the power of the other
-                    // Push them back onto the stack
-                    let value_a = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let value_b = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
+                    let (b, a) = (self.pop(), self.pop());
+                    let result = match (a, b) {
+                        // Integers raised to a negative power result in a float
+                        (Value::Int(a), Value::Int(b)) if b >= 0 => match int_pow(a, b) {
+                            Some(pow) => Value::Int(pow),
+                            None => return Err(self.runtime_error(INTEGER_OVERFLOW)),
+                        },
+                        _ => match (a.as_float(), b.as_float()) {
+                            (Some(a), Some(b)) => Value::Number(a.powf(b)),
+                            _ => return Err(self.runtime_error("Operand must be a number")),
+                        },
                     };
-                    let pow = value_a.powf(value_b);
-                    self.push(Value::Number(pow));
+                    self.push(result);
                 }
                 OpCode::OpTrue => self.push(Value::Bool(true)), // Push `true` onto the stack.
                 OpCode::OpFalse => self.push(Value::Bool(false)), // Push `false` onto the stack.
@@ -251,8 +383,7 @@ impl VM {
                         Value::Bool(true) => Value::Bool(false),
                         Value::Bool(false) => Value::Bool(true),
                         _ => {
-                            self.runtime_error("Operand must be a bool or nil.");
-                            return InterpretResult::InterpretRuneTimeError;
+                            return Err(self.runtime_error("Operand must be a bool or nil."));
                         }
                     };
                     self.push(val);
@@ -265,166 +396,995 @@ impl VM {
                     let value_b = self.pop();
                     self.push(Value::Bool(self.values_equal(value_a, value_b)))
                 }
-                OpCode::OpGreater => {
-                    // Get first 2 values from stack
-                    // compare them and
-                    // Push the result back onto the stack
-                    let value_a = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let value_b = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let result = value_a < value_b;
-                    self.push(Value::Bool(result));
-                }
-                OpCode::OpLess => {
-                    // Get first 2 values from stack
-                    // compare them and
-                    // Push the result back onto the stack
-                    let value_a = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let value_b = match self.pop() {
-                        // Check for valid types
-                        Value::Number(v) => v,
-                        _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    };
-                    let result = value_a > value_b;
-                    self.push(Value::Bool(result));
-                }
-                OpCode::OpPrint => Value::print_value(self.pop()),
+                OpCode::OpGreater => self.compare(|a, b| a > b, |a, b| a > b)?,
+                OpCode::OpLess => self.compare(|a, b| a < b, |a, b| a < b)?,
+                OpCode::OpPrint => self.pop().print_value(&self.heap),
                 OpCode::OpPop => _ = self.pop(),
-                OpCode::OpDefineGlobal(i) => {
-                    let global_name = self.chunk.read_string(i);
-                    let value = self.pop();
-                    self.globals.insert(global_name, value);
-                }
+                OpCode::OpDefineGlobal(i) => self.define_global(i as usize),
+                OpCode::OpDefineGlobalLong(i) => self.define_global(i as usize),
                 OpCode::OpGetGlobal(i) => {
-                    let global_name = self.chunk.read_string(i);
-                    match self.globals.get(&global_name) {
-                        Some(value) => self.push(value.clone()),
-                        None => {
-                            let msg = format!("Undefined variable '{}'.", global_name);
-                            self.runtime_error(&msg);
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    }
+                    self.push_global(i as usize)?;
+                }
+                OpCode::OpGetGlobalLong(i) => {
+                    self.push_global(i as usize)?;
                 }
                 OpCode::OpSetGlobal(i) => {
-                    let global_name = self.chunk.read_string(i);
-                    let value = self.peek(0);
-                    //TODO remove global_name.clone
-                    if self.globals.insert(global_name.clone(), value).is_none() {
-                        self.globals.remove(&global_name);
-                        let msg = format!("Undefined variable '{}'.", global_name);
-                        self.runtime_error(&msg);
-                        return InterpretResult::InterpretRuneTimeError;
-                    }
+                    self.assign_global(i as usize)?;
+                }
+                OpCode::OpSetGlobalLong(i) => {
+                    self.assign_global(i as usize)?;
                 }
                 OpCode::OpGetLocal(i) => {
-                    let local_name = self.chunk.read_string(i);
-                    match self.globals.get(&local_name) {
-                        Some(value) => self.push(value.clone()),
-                        None => {
-                            let msg = format!("Undefined variable '{}'.", local_name);
-                            self.runtime_error(&msg);
-                            return InterpretResult::InterpretRuneTimeError;
-                        }
-                    }
+                    // Locals live in the stack slots of the current frame
+                    let slot = self.frame().slots + i as usize;
+                    self.push(self.stack[slot]);
                 }
                 OpCode::OpSetLocal(i) => {
-                    let value = self.peek(0);
-                    self.stack[i as usize] = value;
+                    let slot = self.frame().slots + i as usize;
+                    self.stack[slot] = self.peek(0);
                 }
                 OpCode::OpJumpIfFalse(offset) => {
                     if self.peek(0).is_falsey() {
-                        self.ip += offset as usize;
+                        self.frame_mut().ip += offset as usize;
                     }
                 }
                 OpCode::OpJump(offset) => {
-                    self.ip += offset as usize;
+                    self.frame_mut().ip += offset as usize;
                 }
                 OpCode::OpLoop(offset) => {
-                    self.ip -= offset as usize + 1;
+                    self.frame_mut().ip -= offset as usize + 1;
+                }
+            }
+        }
+    }
+    /// Compiles and runs `source`. Returns the value the script returned, every
+    /// error found while compiling it, or the error that stopped its execution.
+    /// The VM is left ready to interpret more code either way.
+    pub fn interpret(&mut self, source: &str) -> Result<Value, VeraError> {
+        let function = self.compile(source, false)?;
+        self.run_script(function)
+    }
+
+    /// Like `interpret`, but if `source` ends with an expression statement
+    /// its value is returned instead of `nil`.
+    pub fn evaluate(&mut self, source: &str) -> Result<Value, VeraError> {
+        let function = self.compile(source, true)?;
+        self.run_script(function)
+    }
+
+    /// Forgets every global variable, including natives, and defines the
+    /// built-in procedures again. Objects only reachable through the
+    /// globals are freed by the next collection.
+    pub fn reset(&mut self) -> () {
+        self.globals.clear();
+        self.reset_stack();
+        builtins::define_builtins(self);
+    }
+
+    /// Compiles `source` into the binary `.vbc` format, which
+    /// `interpret_bytes` runs without compiling it again.
+    pub fn compile_to_bytes(&mut self, source: &str) -> Result<Vec<u8>, VeraError> {
+        let function = self.compile(source, false)?;
+        Ok(bytecode::serialize(function, &self.heap))
+    }
+
+    /// Loads a script compiled by `compile_to_bytes` and runs it like `interpret`.
+    pub fn interpret_bytes(&mut self, bytes: &[u8]) -> Result<Value, VeraError> {
+        let function = bytecode::deserialize(bytes, self).map_err(VeraError::Load)?;
+        self.run_script(function)
+    }
+
+    // Run the compiled top level script `function`
+    fn run_script(&mut self, function: ObjRef) -> Result<Value, VeraError> {
+        // The script occupies stack slot zero of its frame, just like any procedure.
+        // The function is kept on the stack while its closure is allocated
+        self.push(Value::Object(function));
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.pop();
+        self.push(Value::Object(closure));
+
+        // Run instructions
+        self.call(closure, 0)?;
+        Ok(self.run(0)?)
+    }
+
+    /// Compiles `source` without running it and prints the byte code of
+    /// the top level script followed by every procedure declared in it.
+    pub fn disassemble(&mut self, source: &str) -> Result<(), VeraError> {
+        let script = self.compile(source, false)?;
+
+        let mut functions = vec![script];
+        while let Some(function) = functions.pop() {
+            let function = self.heap.as_function(function);
+            function
+                .chunk
+                .disassemble(&function.to_string(), &self.heap);
+
+            // Nested procedures are constants of the chunk they are declared in
+            for &constant in function.chunk.constants.array.iter().rev() {
+                if let Value::Object(reference) = constant {
+                    if let Obj::Function(_) = self.heap.get(reference) {
+                        functions.push(reference);
+                    }
                 }
             }
-            // Continue to next instruction
-            self.ip += 1;
         }
+        Ok(())
     }
-    // Interpret a chunk of bytecode
-    pub fn interpret(&mut self, source: &String) -> InterpretResult {
-        // Create new byte chunk to hold incoming instruction
-        let mut chunk = Chunk::new();
 
+    // Compile `source` into the top level script function, `returns_last_expression`
+    // makes a final expression statement the script's result
+    fn compile(
+        &mut self,
+        source: &str,
+        returns_last_expression: bool,
+    ) -> Result<ObjRef, VeraError> {
         // Generate Tokens from source while
         let mut scanner = Scanner::new(source);
         let tokens = scanner.scan_tokens();
 
-        // Create parser
-        let mut parser = Parser::new(tokens, &mut chunk);
+        // Compile tokens into the top level script
+        let mut parser = Parser::new(tokens, self);
+        if returns_last_expression {
+            parser = parser.returning_last_expression();
+        }
+        parser.compile().map_err(VeraError::Compile)
+    }
+
+    /// Sets the global variable `name`, defining it if it doesn't exist yet.
+    pub fn set_global(&mut self, name: &str, value: impl ToValue) -> () {
+        // Keep the name on the stack while the value is converted
+        let name = self.intern(name.to_string());
+        self.push(Value::Object(name));
+        let value = value.to_value(self);
+        self.globals.insert(name, value);
+        self.pop();
+    }
+
+    /// Gets the value of the global variable `name` converted to `T`. Fails with
+    /// a runtime error if it isn't defined or has a different type.
+    pub fn get_global<T: FromValue>(&self, name: &str) -> Result<T, VeraError> {
+        let value = match self
+            .heap
+            .find_string(name)
+            .and_then(|name| self.globals.get(&name))
+        {
+            Some(&value) => value,
+            None => {
+                let msg = format!("Undefined variable '{}'.", name);
+                return Err(RuntimeError::untraced(msg).into());
+            }
+        };
+        T::from_value(value, self).map_err(|msg| RuntimeError::untraced(msg).into())
+    }
+
+    /// Calls the brick, class or native stored in the global `name` with `args`
+    /// and converts the value it returns to `T`. Must not be called while the VM
+    /// is running, for example from a native function.
+    pub fn call_global<T: FromValue>(
+        &mut self,
+        name: &str,
+        args: &[&dyn ToValue],
+    ) -> Result<T, VeraError> {
+        let callee = self.get_global::<Value>(name)?;
+        let arg_count = match u8::try_from(args.len()) {
+            Ok(arg_count) => arg_count,
+            Err(_) => {
+                return Err(self
+                    .runtime_error("Can't have more than 255 arguments.")
+                    .into())
+            }
+        };
 
-        // Compile tokens
-        if !parser.compile() {
-            chunk.free_chunk();
-            return InterpretResult::InterpretCompileError;
+        // The callee and its arguments sit on the stack, just like for `OpCall`,
+        // which also keeps them alive while the remaining arguments are converted
+        self.push(callee);
+        for arg in args {
+            let value = arg.to_value(self);
+            self.push(value);
         }
 
-        // Init vm
-        self.chunk = chunk;
-        self.code = self.chunk.code.clone();
+        let base = self.frames.len();
+        self.call_value(callee, arg_count)?;
+        let result = if self.frames.len() > base {
+            self.run(base)?
+        } else {
+            // Natives and classes without an initializer return right away,
+            // leaving their result in place of the callee
+            self.pop()
+        };
 
-        // Run instructions
-        let result = self.run();
+        T::from_value(result, self).map_err(|msg| self.runtime_error(&msg).into())
+    }
+
+    // Heap the VM allocates its objects on
+    #[cfg(test)]
+    pub(crate) fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Names of every global variable currently defined.
+    pub fn global_names(&self) -> Vec<String> {
+        self.globals
+            .keys()
+            .map(|&name| self.heap.as_string(name).chars.clone())
+            .collect()
+    }
+
+    /// Formats `value` the way `shine` prints it.
+    pub fn format_value(&self, value: Value) -> String {
+        self.heap.format_value(value)
+    }
+
+    /// The items of a list value, `None` if `value` isn't a list.
+    pub fn as_list(&self, value: Value) -> Option<&Vec<Value>> {
+        match value {
+            Value::Object(reference) => match self.heap.get(reference) {
+                Obj::List(list) => Some(&list.items),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The items of a list value for changing them, `None` if `value` isn't a list.
+    pub fn as_list_mut(&mut self, value: Value) -> Option<&mut Vec<Value>> {
+        match value {
+            Value::Object(reference) => match self.heap.get_mut(reference) {
+                Obj::List(list) => Some(&mut list.items),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // The map a value refers to, if it refers to one
+    pub(crate) fn as_map_mut(&mut self, value: Value) -> Option<&mut ObjMap> {
+        match value {
+            Value::Object(reference) => match self.heap.get_mut(reference) {
+                Obj::Map(map) => Some(map),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Reads the characters of a string value, `None` if `value` isn't a string.
+    pub fn as_str(&self, value: Value) -> Option<&str> {
+        match value {
+            Value::Object(reference) => match self.heap.get(reference) {
+                Obj::String(s) => Some(&s.chars),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Define the global named by constant `index` with the value on top of the stack
+    fn define_global(&mut self, index: usize) -> () {
+        let name = self.read_string(index);
+        let value = self.pop();
+        self.globals.insert(name, value);
+    }
+
+    // Push the value of the global named by constant `index`
+    fn push_global(&mut self, index: usize) -> Result<(), RuntimeError> {
+        let name = self.read_string(index);
+        match self.globals.get(&name) {
+            Some(&value) => {
+                self.push(value);
+                Ok(())
+            }
+            None => {
+                let msg = format!("Undefined variable '{}'.", self.heap.as_string(name));
+                Err(self.runtime_error(&msg))
+            }
+        }
+    }
+
+    // Assign the value on top of the stack to the existing global named by constant `index`
+    fn assign_global(&mut self, index: usize) -> Result<(), RuntimeError> {
+        let name = self.read_string(index);
+        let value = self.peek(0);
+        if self.globals.insert(name, value).is_none() {
+            self.globals.remove(&name);
+            let msg = format!("Undefined variable '{}'.", self.heap.as_string(name));
+            return Err(self.runtime_error(&msg));
+        }
+        Ok(())
+    }
+
+    // Call `callee` with the `arg_count` arguments on top of the stack
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), RuntimeError> {
+        // Slot of the callee, which becomes slot zero of the new frame
+        let slot = self.stack.len() - arg_count as usize - 1;
+
+        let callee = match callee {
+            Value::Object(callee) => callee,
+            _ => {
+                return Err(self.runtime_error("Can only call bricks and classes."));
+            }
+        };
+
+        match self.heap.get(callee) {
+            Obj::Closure(_) => self.call(callee, arg_count),
+            Obj::Native(native) => {
+                if arg_count as usize != native.arity {
+                    let msg = format!("Expected {} arguments but got {}.", native.arity, arg_count);
+                    return Err(self.runtime_error(&msg));
+                }
+
+                // The arguments stay on the stack during the call, so they
+                // survive any collection the native function triggers
+                let function = native.function;
+                let args = self.stack[slot + 1..].to_vec();
+                match function(self, &args) {
+                    Ok(result) => {
+                        self.stack.truncate(slot);
+                        self.push(result);
+                        Ok(())
+                    }
+                    Err(msg) => Err(self.runtime_error(&msg)),
+                }
+            }
+            Obj::BoundMethod(bound) => {
+                // Methods find their receiver as `this` in slot zero
+                let method = bound.method;
+                self.stack[slot] = bound.receiver;
+                self.call(method, arg_count)
+            }
+            Obj::Class(class) => {
+                // Calling a class replaces it with a new instance and runs the initializer
+                // No class has an initializer unless its name was interned
+                let initializer = self
+                    .heap
+                    .find_string(INITIALIZER)
+                    .and_then(|init| class.methods.get(&init).copied());
+                let instance = self.alloc(Obj::Instance(ObjInstance::new(callee)));
+                self.stack[slot] = Value::Object(instance);
+
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => {
+                        let msg = format!("Expected 0 arguments but got {}.", arg_count);
+                        Err(self.runtime_error(&msg))
+                    }
+                    None => Ok(()),
+                }
+            }
+            _ => Err(self.runtime_error("Can only call bricks and classes.")),
+        }
+    }
+
+    // Push a new call frame for `closure`, its slots start at the callee itself
+    fn call(&mut self, closure: ObjRef, arg_count: u8) -> Result<(), RuntimeError> {
+        let function = self.heap.as_closure(closure).function;
+        let arity = self.heap.as_function(function).arity;
+        if arg_count as usize != arity {
+            let msg = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(&msg));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        let slots = self.stack.len() - arg_count as usize - 1;
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots,
+        });
+        Ok(())
+    }
+
+    // Call the method `name` on the receiver below the `arg_count` arguments
+    fn invoke(&mut self, name: ObjRef, arg_count: u8) -> Result<(), RuntimeError> {
+        let instance = match self.as_instance(self.peek(arg_count as usize)) {
+            Some(instance) => self.heap.as_instance(instance),
+            None => {
+                return Err(self.runtime_error("Only instances have methods."));
+            }
+        };
+
+        // A field holding a procedure is called like any other value
+        if let Some(&value) = instance.fields.get(&name) {
+            let slot = self.stack.len() - arg_count as usize - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
 
-        return result;
+        let method = self
+            .heap
+            .as_class(instance.class)
+            .methods
+            .get(&name)
+            .copied();
+        match method {
+            Some(method) => self.call(method, arg_count),
+            None => {
+                let msg = format!("Undefined property '{}'.", self.heap.as_string(name));
+                Err(self.runtime_error(&msg))
+            }
+        }
+    }
+
+    // Replace the instance on top of the stack with its method `name`
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), RuntimeError> {
+        let method = match self.heap.as_class(class).methods.get(&name).copied() {
+            Some(method) => method,
+            None => {
+                let msg = format!("Undefined property '{}'.", self.heap.as_string(name));
+                return Err(self.runtime_error(&msg));
+            }
+        };
+
+        // The receiver stays reachable through the new bound method
+        let bound = ObjBoundMethod {
+            receiver: self.pop(),
+            method,
+        };
+        let bound = self.alloc(Obj::BoundMethod(bound));
+        self.push(Value::Object(bound));
+        Ok(())
+    }
+
+    // Read `target[index]` of a list or map
+    fn index_get(&mut self, target: Value, index: Value) -> Result<Value, RuntimeError> {
+        match self.indexed(target) {
+            Some(Obj::List(_)) => {
+                let list = self.as_object(target);
+                let position = self.list_index(list, index)?;
+                Ok(self.heap.as_list(list).items[position])
+            }
+            Some(Obj::Map(_)) => {
+                let key = self.require_key(index)?;
+                match self.heap.as_map(self.as_object(target)).get(key) {
+                    Some(value) => Ok(value),
+                    None => {
+                        let msg = format!("Undefined key '{}'.", self.heap.format_value(index));
+                        Err(self.runtime_error(&msg))
+                    }
+                }
+            }
+            _ => Err(self.runtime_error("Only lists and maps can be indexed.")),
+        }
+    }
+
+    // Assign `target[index] = value` of a list or map
+    fn index_set(&mut self, target: Value, index: Value, value: Value) -> Result<(), RuntimeError> {
+        match self.indexed(target) {
+            Some(Obj::List(_)) => {
+                let list = self.as_object(target);
+                let position = self.list_index(list, index)?;
+                self.heap.as_list_mut(list).items[position] = value;
+            }
+            Some(Obj::Map(_)) => {
+                let key = self.require_key(index)?;
+                let map = self.as_object(target);
+                self.heap.as_map_mut(map).insert(key, index, value);
+            }
+            _ => return Err(self.runtime_error("Only lists and maps can be indexed.")),
+        }
+        Ok(())
+    }
+
+    // The object an indexed value refers to
+    fn indexed(&self, target: Value) -> Option<&Obj> {
+        match target {
+            Value::Object(reference) => Some(self.heap.get(reference)),
+            _ => None,
+        }
+    }
+
+    fn as_object(&self, value: Value) -> ObjRef {
+        match value {
+            Value::Object(reference) => reference,
+            _ => panic!("Value is not an object!"),
+        }
+    }
+
+    // Hashable form of a map key, `None` if `value` can't be a key
+    pub(crate) fn map_key(&self, value: Value) -> Option<MapKey> {
+        MapKey::new(value, &self.heap)
+    }
+
+    // Hashable form of a map key, a runtime error if `value` can't be a key
+    fn require_key(&mut self, value: Value) -> Result<MapKey, RuntimeError> {
+        match self.map_key(value) {
+            Some(key) => Ok(key),
+            None => Err(self.runtime_error(UNHASHABLE_KEY)),
+        }
+    }
+
+    // Position of `index` in `list`, negative indices count from the end
+    fn list_index(&mut self, list: ObjRef, index: Value) -> Result<usize, RuntimeError> {
+        let index = match index.as_int() {
+            Some(index) => index,
+            None => return Err(self.runtime_error("List index must be an integer.")),
+        };
+
+        let len = self.heap.as_list(list).items.len() as i64;
+        let position = if index < 0 { index + len } else { index };
+        if position < 0 || position >= len {
+            let msg = format!(
+                "List index {} out of range for a list of length {}.",
+                index, len
+            );
+            return Err(self.runtime_error(&msg));
+        }
+        Ok(position as usize)
+    }
+
+    // Get the upvalue for the variable in stack slot `slot`. Procedures
+    // capturing the same variable share its upvalue
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        for &upvalue in self.open_upvalues.iter() {
+            if let ObjUpvalue::Open(open) = *self.heap.as_upvalue(upvalue) {
+                if open == slot {
+                    return upvalue;
+                }
+            }
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    // Close every upvalue pointing at stack slot `last` or above by
+    // copying the variable off the stack into the upvalue
+    fn close_upvalues(&mut self, last: usize) -> () {
+        let (stack, heap) = (&self.stack, &mut self.heap);
+        self.open_upvalues.retain(|&upvalue| {
+            let upvalue = heap.as_upvalue_mut(upvalue);
+            match *upvalue {
+                ObjUpvalue::Open(slot) if slot >= last => {
+                    *upvalue = ObjUpvalue::Closed(stack[slot]);
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    // Get the string object holding `chars`, allocating it only if
+    // no identical string exists on the heap yet
+    pub(crate) fn intern(&mut self, chars: String) -> ObjRef {
+        return self.intern_with_roots(chars, std::iter::empty());
+    }
+
+    // Intern `chars`, treating `roots` as reachable if a collection runs
+    pub(crate) fn intern_with_roots<I>(&mut self, chars: String, roots: I) -> ObjRef
+    where
+        I: IntoIterator<Item = Value>,
+    {
+        if let Some(reference) = self.heap.find_string(&chars) {
+            return reference;
+        }
+        return self.alloc_with_roots(Obj::String(ObjString { chars }), roots);
+    }
+
+    // Move an object onto the heap, collecting garbage first if the heap grew too large
+    pub(crate) fn alloc(&mut self, obj: Obj) -> ObjRef {
+        return self.alloc_with_roots(obj, std::iter::empty());
+    }
+
+    // Move an object onto the heap. `roots` are values the VM doesn't know
+    // about yet, like the constants of procedures still being compiled.
+    // They are only walked when a collection actually runs
+    pub(crate) fn alloc_with_roots<I>(&mut self, obj: Obj, roots: I) -> ObjRef
+    where
+        I: IntoIterator<Item = Value>,
+    {
+        let reference = self.heap.alloc(obj);
+
+        if self.heap.should_collect() {
+            // The new object isn't referenced by anything yet
+            self.heap.mark_object(reference);
+            for value in roots {
+                self.heap.mark_value(value);
+            }
+            self.collect_garbage();
+        }
+
+        reference
+    }
+
+    // Free every object that can't be reached from the VM's roots:
+    // the value stack, the call frames, the open upvalues and the globals
+    fn collect_garbage(&mut self) -> () {
+        for &value in self.stack.iter() {
+            self.heap.mark_value(value);
+        }
+        for frame in self.frames.iter() {
+            self.heap.mark_object(frame.closure);
+        }
+        for &upvalue in self.open_upvalues.iter() {
+            self.heap.mark_object(upvalue);
+        }
+        for (&name, &value) in self.globals.iter() {
+            self.heap.mark_object(name);
+            self.heap.mark_value(value);
+        }
+
+        self.heap.trace_references();
+        self.heap.sweep();
+    }
+
+    // Print the value stack and the instruction at `ip` of `function`
+    fn trace_instruction(&self, function: ObjRef, ip: usize) -> () {
+        let stack: String = self
+            .stack
+            .iter()
+            .map(|&value| format!("[ {} ]", self.heap.format_value(value)))
+            .collect();
+        println!("          {}", stack);
+
+        let chunk = &self.heap.as_function(function).chunk;
+        println!("{}", chunk.trace_instruction(ip, &self.heap));
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No active call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("No active call frame")
+    }
+
+    // Chunk of the procedure currently being executed
+    fn chunk(&self) -> &Chunk {
+        &self.heap.as_function(self.frame().function).chunk
+    }
+
+    // Upvalue `index` of the closure currently being executed
+    fn frame_upvalue(&self, index: u8) -> ObjRef {
+        self.heap.as_closure(self.frame().closure).upvalues[index as usize]
+    }
+
+    // Read the string constant `index` of the current chunk
+    fn read_string(&self, index: usize) -> ObjRef {
+        match self.chunk().read_constant(index) {
+            Value::Object(reference) => reference,
+            _ => panic!("Constant is not String!"),
+        }
+    }
+
+    fn is_string(&self, reference: ObjRef) -> bool {
+        matches!(self.heap.get(reference), Obj::String(_))
+    }
+
+    // The instance a value refers to, if it refers to one
+    fn as_instance(&self, value: Value) -> Option<ObjRef> {
+        match value {
+            Value::Object(reference) => match self.heap.get(reference) {
+                Obj::Instance(_) => Some(reference),
+                _ => None,
+            },
+            _ => None,
+        }
     }
 
     // push onto value stack
-    pub fn push(&mut self, value: Value) -> () {
+    pub(crate) fn push(&mut self, value: Value) -> () {
         self.stack.push(value);
     }
 
     // pop from value stack
-    pub fn pop(&mut self) -> Value {
+    pub(crate) fn pop(&mut self) -> Value {
         self.stack.pop().expect("Couldn't Pop from VM stack")
     }
 
     fn peek(&self, n: usize) -> Value {
         let size = self.stack.len();
-        self.stack[size - 1 - n].clone()
+        self.stack[size - 1 - n]
     }
 
-    // Check if two value types are equal
+    // Check if two values are equal. Values of different types are never equal and
+    // objects are compared by identity, which covers strings since they are interned
     fn values_equal(&self, a: Value, b: Value) -> bool {
-        // Check if the two values have the same type
-        if a.type_id() != b.type_id() {
-            return false;
+        match (a, b) {
+            // Integers equal the floats with the same value
+            (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => a as f64 == b,
+            _ => a == b,
+        }
+    }
+
+    // Pop two numbers and push the result of `int` if both are integers,
+    // otherwise of `float` with integers promoted. `int` returns `None` on overflow
+    fn arithmetic(
+        &mut self,
+        int: fn(i64, i64) -> Option<i64>,
+        float: fn(f64, f64) -> f64,
+    ) -> Result<(), RuntimeError> {
+        let (b, a) = (self.pop(), self.pop());
+        let result = match (a, b) {
+            (Value::Int(a), Value::Int(b)) => match int(a, b) {
+                Some(result) => Value::Int(result),
+                None => return Err(self.runtime_error(INTEGER_OVERFLOW)),
+            },
+            _ => match (a.as_float(), b.as_float()) {
+                (Some(a), Some(b)) => Value::Number(float(a, b)),
+                _ => return Err(self.runtime_error("Operand must be a number")),
+            },
+        };
+        self.push(result);
+        Ok(())
+    }
+
+    // Pop two integers and push `a` shifted by `b` bits. Floats with a
+    // fractional part can't be shifted. `op` returns `None` on overflow
+    fn shift(&mut self, op: fn(i64, u32) -> Option<i64>) -> Result<(), RuntimeError> {
+        let (b, a) = (self.pop(), self.pop());
+        let (a, b) = match (a.as_int(), b.as_int()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Err(self.runtime_error("Operands of a bit shift must be integers.")),
+        };
+        let b = match u32::try_from(b) {
+            Ok(b) if b < i64::BITS => b,
+            _ => return Err(self.runtime_error("Shift amount must be between 0 and 63.")),
+        };
+        match op(a, b) {
+            Some(result) => self.push(Value::Int(result)),
+            None => return Err(self.runtime_error(INTEGER_OVERFLOW)),
+        }
+        Ok(())
+    }
+
+    // Pop two integers and push the result of `op`, floats
+    // with a fractional part are rejected like for bit shifts
+    fn bitwise(&mut self, op: fn(i64, i64) -> i64) -> Result<(), RuntimeError> {
+        let (b, a) = (self.pop(), self.pop());
+        match (a.as_int(), b.as_int()) {
+            (Some(a), Some(b)) => self.push(Value::Int(op(a, b))),
+            _ => return Err(self.runtime_error("Operands must be integers.")),
+        }
+        Ok(())
+    }
+
+    // Pop two numbers and push whether `int` or `float` holds for them,
+    // integers are only promoted when compared with floats
+    fn compare(
+        &mut self,
+        int: fn(i64, i64) -> bool,
+        float: fn(f64, f64) -> bool,
+    ) -> Result<(), RuntimeError> {
+        let (b, a) = (self.pop(), self.pop());
+        let result = match (a, b) {
+            (Value::Int(a), Value::Int(b)) => int(a, b),
+            _ => match (a.as_float(), b.as_float()) {
+                (Some(a), Some(b)) => float(a, b),
+                _ => return Err(self.runtime_error("Operand must be a number")),
+            },
+        };
+        self.push(Value::Bool(result));
+        Ok(())
+    }
+
+    // Build the error for a failed instruction, tracing the active calls,
+    // and reset the VM so it can run more code afterwards
+    fn runtime_error(&mut self, msg: &str) -> RuntimeError {
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self.heap.as_function(frame.function);
+                // The frame's ip already points past the failing instruction or call
+                TraceFrame {
+                    line: function.chunk.line[frame.ip.saturating_sub(1)],
+                    function: function.name.clone(),
+                }
+            })
+            .collect();
+
+        self.reset_stack();
+        RuntimeError {
+            message: msg.to_string(),
+            line: trace.first().map_or(0, |frame| frame.line),
+            trace,
+        }
+    }
+
+    // Drop all values and call frames left behind by a failed execution
+    // Closures that escaped keep the values of the variables they captured
+    fn reset_stack(&mut self) -> () {
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+    }
+}
+
+// Remainder of `a / b` with the sign of `b`, so `-7 % 3` is `2`. `b`
+// isn't zero, and `i64::MIN % -1` is `0` where plain `%` would overflow
+fn int_modulo(a: i64, b: i64) -> Option<i64> {
+    let remainder = a.wrapping_rem(b);
+    if remainder != 0 && (remainder < 0) != (b < 0) {
+        Some(remainder + b)
+    } else {
+        Some(remainder)
+    }
+}
+
+// Remainder of `a / b` with the sign of `b`, like for integers
+fn float_modulo(a: f64, b: f64) -> f64 {
+    let remainder = a % b;
+    if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+        remainder + b
+    } else {
+        remainder
+    }
+}
+
+// `base` raised to the non-negative power `exponent`, `None` on overflow
+fn int_pow(base: i64, exponent: i64) -> Option<i64> {
+    match u32::try_from(exponent) {
+        Ok(exponent) => base.checked_pow(exponent),
+        // Only these bases don't overflow for huge exponents
+        Err(_) => match base {
+            0 | 1 => Some(base),
+            -1 => Some(if exponent % 2 == 0 { 1 } else { -1 }),
+            _ => None,
+        },
+    }
+}
+
+// ### TESTS ###
+
+#[cfg(test)]
+mod tests {
+
+    use crate::error::{RuntimeError, VeraError};
+    use crate::value::Value;
+    use crate::vm::VM;
+
+    fn add(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+        match (args[0].as_float(), args[1].as_float()) {
+            (Some(a), Some(b)) => Ok(Value::Number(a + b)),
+            _ => Err("Operands must be numbers.".to_string()),
+        }
+    }
+
+    fn global(vm: &VM, name: &str) -> Option<Value> {
+        vm.get_global(name).ok()
+    }
+
+    #[test]
+    fn test_native_call() {
+        let mut vm = VM::new();
+        vm.define_native("add", 2, add);
+
+        assert!(vm.interpret("pink sum = add(1, add(2, 3));").is_ok());
+        assert_eq!(global(&vm, "sum"), Some(Value::Number(6.0)));
+
+        // Natives are values like any other
+        assert!(vm.interpret("pink f = add; pink sum = f(4, 5);").is_ok());
+        assert_eq!(global(&vm, "sum"), Some(Value::Number(9.0)));
+    }
+
+    #[test]
+    fn test_native_errors() {
+        let mut vm = VM::new();
+        vm.define_native("add", 2, add);
+
+        match vm.interpret("pink ok = 1;\nadd(1, \"two\");") {
+            Err(VeraError::Runtime(error)) => {
+                assert_eq!(error.message, "Operands must be numbers.");
+                assert_eq!(error.line, 2);
+            }
+            _ => panic!("Expected a runtime error"),
+        }
+
+        match vm.interpret("add(1);") {
+            Err(VeraError::Runtime(error)) => {
+                assert_eq!(error.message, "Expected 2 arguments but got 1.")
+            }
+            _ => panic!("Expected a runtime error"),
+        }
+
+        // The VM keeps working after a failed native call
+        assert!(vm.interpret("pink sum = add(1, 1);").is_ok());
+        assert_eq!(global(&vm, "sum"), Some(Value::Number(2.0)));
+    }
+
+    #[test]
+    fn test_globals_from_rust() {
+        let mut vm = VM::new();
+        vm.set_global("band", "Pink Floyd");
+        vm.set_global("members", 4.0);
+        vm.set_global("albums", 15i64);
+        vm.set_global("touring", Some(true));
+        vm.set_global("album", None::<String>);
+
+        assert!(vm
+            .interpret("pink line = band + \" has \"; members = members + 1; albums++;")
+            .is_ok());
+        assert_eq!(
+            vm.get_global::<String>("line"),
+            Ok("Pink Floyd has ".to_string())
+        );
+        assert_eq!(vm.get_global::<f64>("members"), Ok(5.0));
+        assert_eq!(vm.get_global::<i64>("albums"), Ok(16));
+        assert_eq!(vm.get_global::<f64>("albums"), Ok(16.0));
+        assert_eq!(vm.get_global::<Option<bool>>("touring"), Ok(Some(true)));
+        assert_eq!(vm.get_global::<Option<String>>("album"), Ok(None));
+
+        assert_eq!(
+            vm.get_global::<i64>("members"),
+            Err(VeraError::Runtime(RuntimeError::untraced(
+                "Value is not an integer.".to_string()
+            )))
+        );
+        assert_eq!(
+            vm.get_global::<bool>("members"),
+            Err(VeraError::Runtime(RuntimeError::untraced(
+                "Value is not a bool.".to_string()
+            )))
+        );
+        assert_eq!(
+            vm.get_global::<f64>("drummer"),
+            Err(VeraError::Runtime(RuntimeError::untraced(
+                "Undefined variable 'drummer'.".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn test_call_global() {
+        let mut vm = VM::new();
+        vm.define_native("add", 2, add);
+        let source = "brick greet(name, times) {
+            pink greeting = \"\";
+            time(pink i = 0; i < times; i = i + 1) {
+                greeting = greeting + \"Hey \" + name + \"! \";
+            }
+            goodbye greeting;
+        }";
+        assert!(vm.interpret(source).is_ok());
+
+        let greeting: String = vm.call_global("greet", &[&"you", &2.0]).unwrap();
+        assert_eq!(greeting, "Hey you! Hey you! ");
+        let sum: f64 = vm.call_global("add", &[&1.0, &2.0]).unwrap();
+        assert_eq!(sum, 3.0);
+
+        match vm.call_global::<String>("greet", &[&"you"]) {
+            Err(VeraError::Runtime(error)) => {
+                assert_eq!(error.message, "Expected 2 arguments but got 1.")
+            }
+            _ => panic!("Expected a runtime error"),
         }
 
-        return a == b;
+        // The VM is still usable after a failed call
+        let sum: f64 = vm.call_global("add", &[&sum, &1.0]).unwrap();
+        assert_eq!(sum, 4.0);
     }
 
-    fn runtime_error(&self, msg: &str) -> () {
-        println!("{}", msg);
+    #[test]
+    fn test_escaped_closure_after_error() {
+        let mut vm = VM::new();
+        // `count` is still on the stack when the error happens
+        let source = "pink counter = money;
+        brick start() {
+            pink count = 0;
+            brick next() {
+                count = count + 1;
+                goodbye count;
+            }
+            counter = next;
+            counter();
+            goodbye money + 1;
+        }
+        start();";
+        assert!(matches!(vm.interpret(source), Err(VeraError::Runtime(_))));
+
+        assert!(vm.interpret("pink second = counter();").is_ok());
+        assert_eq!(global(&vm, "second"), Some(Value::Int(2)));
+        let third: i64 = vm.call_global("counter", &[]).unwrap();
+        assert_eq!(third, 3);
     }
 }
@@ -2,7 +2,1098 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file.
 
-//use pf::vm::VM;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// Run a Vera script through the `pf` binary and return what it printed
+fn run(name: &str, source: &str) -> String {
+    return run_with_args(name, &[], source);
+}
+
+// Run a Vera script, passing `args` to `pf` before the script path
+fn run_with_args(name: &str, args: &[&str], source: &str) -> String {
+    let output = execute(name, args, source);
+    String::from_utf8(output.stdout).expect("Output is not valid UTF-8")
+}
+
+// Run a Vera script and return the errors it reported
+fn run_err(name: &str, source: &str) -> String {
+    let output = execute(name, &[], source);
+    String::from_utf8(output.stderr).expect("Output is not valid UTF-8")
+}
+
+fn execute(name: &str, args: &[&str], source: &str) -> Output {
+    let path = env::temp_dir().join(format!("vera_{}_{}.ve", name, std::process::id()));
+    fs::write(&path, source).expect("Unable to write test script");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_pf"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .expect("Unable to run pf");
+    fs::remove_file(&path).ok();
+
+    output
+}
+
+#[test]
+fn test_expressions() {
+    let output = run(
+        "expressions",
+        "shine 1 + 2;
+        shine 5 - 3;
+        shine 2 * 3;
+        shine 6 / 4;
+        shine 2 ^ 3;
+        shine 1 < 2;
+        shine \"Hey \" + \"you\";
+        ",
+    );
+
+    assert_eq!(output, "3\n2\n6\n1.5\n8\ntrue\nHey you\n");
+}
+
+#[test]
+fn test_global_procedures() {
+    let output = run(
+        "global_procedures",
+        "pink count = 0;
+        brick bump() {
+            count = count + 1;
+            goodbye count;
+        }
+        brick twice() {
+            bump();
+            goodbye bump();
+        }
+        pink n = 5;
+        pink product = 1;
+        brick factorial() {
+            outThere?(n < 2) goodbye product;
+            product = product * n;
+            n = n - 1;
+            goodbye factorial();
+        }
+        brick nothing() {}
+
+        shine bump();
+        shine twice();
+        shine factorial();
+        shine nothing();
+        shine bump;
+        ",
+    );
+
+    assert_eq!(output, "1\n3\n120\nNil\n<brick bump>\n");
+}
+
+#[test]
+fn test_procedures() {
+    let output = run(
+        "procedures",
+        "brick add(a, b) {
+            goodbye a + b;
+        }
+        brick fib(n) {
+            outThere?(n < 2) goodbye n;
+            goodbye fib(n - 1) + fib(n - 2);
+        }
+        brick nothing() {}
+
+        pink x = add(1, 2);
+        shine x;
+        shine fib(10);
+        shine nothing();
+        shine add;
+        ",
+    );
+
+    assert_eq!(output, "3\n55\nNil\n<brick add>\n");
+}
+
+#[test]
+fn test_procedure_arity() {
+    let output = run_err(
+        "procedure_arity",
+        "brick add(a, b) {
+            goodbye a + b;
+        }
+        shine add(1);
+        ",
+    );
+
+    assert_eq!(
+        output,
+        "Expected 2 arguments but got 1.\n[line 4] in script\n"
+    );
+}
+
+#[test]
+fn test_local_shadowing() {
+    let output = run(
+        "local_shadowing",
+        "pink a = \"global\";
+        {
+            pink a = \"outer\";
+            {
+                pink a = \"inner\";
+                shine a;
+            }
+            shine a;
+        }
+        shine a;
+        ",
+    );
+
+    assert_eq!(output, "inner\nouter\nglobal\n");
+}
+
+#[test]
+fn test_nested_scopes() {
+    let output = run(
+        "nested_scopes",
+        "pink one = 1;
+        {
+            pink x = 1;
+            pink y = 2;
+            {
+                pink z = x + y;
+                x = z * 10;
+                shine z;
+            }
+            shine x + one;
+        }
+        brick f(a) {
+            pink b = a + 1;
+            {
+                pink c = b + 1;
+                goodbye a + b + c;
+            }
+        }
+        shine f(1);
+        ",
+    );
+
+    assert_eq!(output, "3\n31\n6\n");
+}
+
+#[test]
+fn test_loop_locals() {
+    let output = run(
+        "loop_locals",
+        "time(pink i = 0; i < 3; i = i + 1) {
+            pink square = i * i;
+            shine square;
+        }
+        time(pink i = 5; i < 6;) {
+            shine i;
+            i = i + 1;
+        }
+        ",
+    );
+
+    assert_eq!(output, "0\n1\n4\n5\n");
+}
+
+#[test]
+fn test_local_own_initializer() {
+    let output = run_err(
+        "local_own_initializer",
+        "{
+            pink a = a;
+        }
+        ",
+    );
+
+    assert!(output.starts_with("[line 2:"));
+    assert!(output.ends_with("Error at 'a': Can't read local variable in its own initializer.\n"));
+}
+
+#[test]
+fn test_classes() {
+    let output = run(
+        "classes",
+        "class Band {
+            brick init(name, members) {
+                this.name = name;
+                this.members = members;
+            }
+            brick describe() {
+                goodbye this.name + \" is a band\";
+            }
+            brick grow() {
+                this.members = this.members + 1;
+                goodbye this;
+            }
+        }
+        pink floyd = Band(\"Pink Floyd\", 4);
+        shine floyd.describe();
+        shine floyd.grow().members;
+        pink describe = floyd.describe;
+        shine describe();
+        floyd.album = \"Animals\";
+        shine floyd.album;
+        shine floyd;
+        shine Band;
+        ",
+    );
+
+    assert_eq!(
+        output,
+        "Pink Floyd is a band\n5\nPink Floyd is a band\nAnimals\n<Band instance>\nBand\n"
+    );
+}
+
+#[test]
+fn test_class_errors() {
+    let output = run_err(
+        "class_errors",
+        "class Empty {}
+        shine Empty().missing;
+        ",
+    );
+    assert_eq!(
+        output,
+        "Undefined property 'missing'.\n[line 2] in script\n"
+    );
+
+    let output = run_err(
+        "this_outside_class",
+        "shine this;
+        ",
+    );
+    assert!(output.ends_with("Can't use 'this' outside of a class.\n"));
+}
+
+#[test]
+fn test_closures() {
+    let output = run(
+        "closures",
+        "brick makeCounter() {
+            pink count = 0;
+            brick increment() {
+                count = count + 1;
+                goodbye count;
+            }
+            goodbye increment;
+        }
+        pink first = makeCounter();
+        pink second = makeCounter();
+        shine first();
+        shine first();
+        shine second();
+
+        brick outer() {
+            pink x = \"outside\";
+            brick middle() {
+                brick inner() {
+                    shine x;
+                }
+                goodbye inner;
+            }
+            goodbye middle;
+        }
+        outer()()();
+        ",
+    );
+
+    assert_eq!(output, "1\n2\n1\noutside\n");
+}
+
+#[test]
+fn test_closed_upvalues() {
+    let output = run(
+        "closed_upvalues",
+        "pink get;
+        pink set;
+        {
+            pink shared = 1;
+            brick g() {
+                goodbye shared;
+            }
+            brick s(v) {
+                shared = v;
+            }
+            get = g;
+            set = s;
+        }
+        set(42);
+        shine get();
+
+        pink saved;
+        time(pink i = 0; i < 3; i = i + 1) {
+            pink j = i;
+            brick f() {
+                shine j;
+            }
+            outThere?(i == 1) saved = f;
+        }
+        saved();
+        ",
+    );
+
+    assert_eq!(output, "42\n1\n");
+}
+
+#[test]
+fn test_first_class_procedures() {
+    let output = run(
+        "first_class_procedures",
+        "brick add(a, b) {
+            goodbye a + b;
+        }
+        brick sub(a, b) {
+            goodbye a - b;
+        }
+        brick handlers(kind) {
+            outThere?(kind == \"add\") goodbye add;
+            goodbye sub;
+        }
+        brick apply(f, x) {
+            goodbye f(x, x);
+        }
+
+        shine handlers(\"add\")(1, 2);
+        shine handlers(\"sub\")(5, 2);
+        shine apply(add, 4);
+        pink op = sub;
+        shine op(10, 1);
+        shine op == sub;
+        shine op == add;
+        ",
+    );
+
+    assert_eq!(output, "3\n3\n8\n9\ntrue\nfalse\n");
+}
+
+#[test]
+fn test_call_non_procedure() {
+    let output = run_err(
+        "call_non_procedure",
+        "pink lyric = \"Shine on\";
+        lyric();
+        ",
+    );
+
+    assert_eq!(
+        output,
+        "Can only call bricks and classes.\n[line 2] in script\n"
+    );
+}
+
+// Exercises strings, closures, classes and bound methods while
+// creating plenty of garbage along the way
+const GARBAGE_SCRIPT: &str = "class Pig {
+    brick init(name) {
+        this.name = name;
+    }
+    brick oink() {
+        goodbye this.name + \" says oink\";
+    }
+}
+brick makeCounter() {
+    pink count = 0;
+    brick increment() {
+        count = count + 1;
+        goodbye count;
+    }
+    goodbye increment;
+}
+pink counter = makeCounter();
+pink pig;
+pink lyric = \"\";
+time(pink i = 0; i < 200; i = i + 1) {
+    pig = Pig(\"pig\" + \"!\");
+    pink oink = pig.oink;
+    lyric = oink();
+    counter();
+}
+shine lyric;
+shine counter();
+shine pig.name == \"pig!\";
+";
+
+#[test]
+fn test_gc_stress() {
+    let expected = "pig! says oink\n201\ntrue\n";
+
+    // Collecting on every allocation must not free anything still in use
+    let output = run_with_args("gc_stress", &["--gc-stress"], GARBAGE_SCRIPT);
+    assert_eq!(output, expected);
+
+    let output = run_with_args("gc_threshold", &["--gc-threshold", "2048"], GARBAGE_SCRIPT);
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn test_string_equality() {
+    let output = run(
+        "string_equality",
+        "pink a = \"Hey \" + \"you\";
+        pink b = \"Hey you\";
+        shine a == b;
+        shine a == \"Hey \";
+        shine \"money\" + \"\" == \"money\";
+        ",
+    );
+
+    assert_eq!(output, "true\nfalse\ntrue\n");
+}
+
+#[test]
+fn test_many_constants() {
+    // More globals and distinct numbers than a one byte operand can index
+    let mut source = String::new();
+    for i in 0..300 {
+        source.push_str(&format!("pink track{} = {}.5;\n", i, i));
+    }
+    source.push_str(
+        "class Album {}
+        pink album = Album();
+        album.title = \"The Wall\";
+        shine track0 + track299;
+        track299 = 1;
+        shine track299;
+        shine album.title;
+        ",
+    );
+
+    let output = run("many_constants", &source);
+    assert_eq!(output, "300.0\n1\nThe Wall\n");
+}
+
+#[test]
+fn test_runtime_error_trace() {
+    let source = "brick inner(a) {
+        shine \"before\";
+        goodbye a + nope;
+    }
+    brick outer() {
+        goodbye inner(1);
+    }
+    outer();
+    shine \"after\";
+    ";
+
+    // The error names the failing line and every active call, innermost first
+    let output = run_err("runtime_error_trace", source);
+    assert_eq!(
+        output,
+        "Undefined variable 'nope'.\n\
+         [line 3] in brick inner\n\
+         [line 6] in brick outer\n\
+         [line 8] in script\n"
+    );
+
+    // Execution stops at the error
+    let output = run("runtime_error_trace_output", source);
+    assert_eq!(output, "before\n");
+}
+
+#[test]
+fn test_exit_codes() {
+    let output = execute("exit_ok", &[], "shine 1;\n");
+    assert_eq!(output.status.code(), Some(0));
+
+    // Every compile error is reported, not just the first one
+    let output = execute(
+        "exit_compile_error",
+        &[],
+        "pink = 1;
+        shine 2;
+        shine this;
+        shine 3",
+    );
+    assert_eq!(output.status.code(), Some(65));
+    assert!(output.stdout.is_empty());
+    let errors = String::from_utf8(output.stderr).expect("Output is not valid UTF-8");
+    let errors: Vec<&str> = errors.lines().collect();
+    assert_eq!(errors.len(), 3);
+    assert!(errors[0].starts_with("[line 1:"));
+    assert!(errors[0].contains("Error at '='"));
+    assert!(errors[1].ends_with("Error at 'this': Can't use 'this' outside of a class."));
+    assert!(errors[2].starts_with("[line 4:"));
+    assert!(errors[2].contains("Error at end"));
+
+    let output = execute("exit_runtime_error", &[], "shine 1;\nshine -\"a\";\n");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(output.stdout, b"1\n");
+}
+
+#[test]
+fn test_disassemble() {
+    let output = run_with_args(
+        "disassemble",
+        &["--disassemble"],
+        "brick add(a, b) {
+            goodbye a + b;
+        }
+        shine add(1, 2);
+        ",
+    );
+    // The script is compiled but not run
+    assert!(!output.contains("\n3\n"));
+    assert!(output.starts_with("== <script> ==\n"));
+    assert!(output.contains("OpClosure             1 '<brick add>'"));
+    assert!(output.contains("== <brick add> ==\n0000    2 OpGetLocal            1\n"));
+    assert!(output.contains("OpAdd"));
+}
+
+#[test]
+fn test_trace() {
+    let output = run_with_args("trace", &["--trace"], "shine 1 + 2;\n");
+    assert!(output.contains("0000    1 OpConstant            0 '1'\n"));
+    assert!(output.contains("          [ <script> ][ 1 ][ 2 ]\n0002    1 OpAdd\n"));
+    assert!(output.contains("0003    1 OpPrint\n3\n"));
+}
+
+#[test]
+fn test_compile_and_run_bytecode() {
+    let dir = env::temp_dir();
+    let source = dir.join(format!("vera_bytecode_{}.ve", std::process::id()));
+    let compiled = dir.join(format!("vera_bytecode_{}.vbc", std::process::id()));
+    fs::write(
+        &source,
+        "brick greet(name) { goodbye \"Hey \" + name; }
+        shine greet(\"you\");
+        ",
+    )
+    .expect("Unable to write test script");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_pf"))
+        .arg("compile")
+        .arg(&source)
+        .arg("-o")
+        .arg(&compiled)
+        .status()
+        .expect("Unable to run pf");
+    assert!(status.success());
+
+    let run = |path: &std::path::Path| {
+        Command::new(env!("CARGO_BIN_EXE_pf"))
+            .arg("run")
+            .arg(path)
+            .output()
+            .expect("Unable to run pf")
+    };
+    let output = run(&compiled);
+    assert_eq!(output.stdout, b"Hey you\n");
+
+    // A truncated file is an error, not a crash
+    let bytes = fs::read(&compiled).expect("Unable to read byte code");
+    fs::write(&compiled, &bytes[..bytes.len() / 2]).expect("Unable to write byte code");
+    let output = run(&compiled);
+    assert_eq!(output.status.code(), Some(65));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Unable to load byte code:"));
+
+    fs::remove_file(&source).ok();
+    fs::remove_file(&compiled).ok();
+}
+
+// Type `input` into the REPL and return what it printed, prompts included
+fn run_repl(input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pf"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Unable to run pf");
+    child
+        .stdin
+        .take()
+        .expect("REPL has no stdin")
+        .write_all(input.as_bytes())
+        .expect("Unable to write to the REPL");
+    child.wait_with_output().expect("Unable to run pf")
+}
+
+#[test]
+fn test_repl() {
+    let output = run_repl(
+        "pink a = 20;
+a + 1;
+brick double(x) {
+  goodbye x * 2;
+}
+double(
+  a
+);
+shine \"printed\";
+:reset
+a;
+",
+    );
+    let stdout = String::from_utf8(output.stdout).expect("Output is not valid UTF-8");
+    let stderr = String::from_utf8(output.stderr).expect("Output is not valid UTF-8");
+
+    // Values of expression statements are printed, `nil` isn't
+    assert_eq!(stdout, "> > 21\n> ... ... > ... ... 40\n> printed\n> > > ");
+    // Globals are forgotten after `:reset`
+    assert!(stderr.starts_with("Undefined variable 'a'."));
+}
+
+#[test]
+fn test_repl_unbraced_bodies() {
+    // An expression statement that is the body of a statement isn't the entry's value
+    let output = run_repl(
+        "outThere?(1 == 1) 5;
+outThere?(1 == 2) 5;
+pink i = 0; echoes(i < 2) i = i + 1;
+time (pink j = 0; j < 2; j = j + 1) i = i + 1;
+i;
+",
+    );
+    let stdout = String::from_utf8(output.stdout).expect("Output is not valid UTF-8");
+    let stderr = String::from_utf8(output.stderr).expect("Output is not valid UTF-8");
+
+    assert_eq!(stdout, "> > > > > 4\n> ");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_repl_utf8() {
+    let output = run_repl(
+        "shine \"héllo wörld ✓\";
+pink café = \"crème\";
+café + \" brûlée\";
+",
+    );
+    let stdout = String::from_utf8(output.stdout).expect("Output is not valid UTF-8");
+    let stderr = String::from_utf8(output.stderr).expect("Output is not valid UTF-8");
+
+    assert_eq!(stdout, "> héllo wörld ✓\n> > crème brûlée\n> ");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_lists() {
+    let output = run(
+        "lists",
+        "pink xs = [1, 2, \"three\"];
+        shine xs;
+        shine xs[0] + xs[-2];
+        xs[2] = [4, 5];
+        shine xs[2][1];
+        push(xs, 6);
+        shine len(xs);
+        shine pop(xs);
+        pink sum = 0;
+        time (pink i = 0; i < len(xs); i = i + 1) {
+            outThere?(i < 2) sum = sum + xs[i];
+        }
+        shine sum;
+        push(xs, xs);
+        shine xs;
+        shine [];
+        ",
+    );
+    assert_eq!(
+        output,
+        "[1, 2, \"three\"]\n3\n5\n4\n6\n3\n[1, 2, [4, 5], [...]]\n[]\n"
+    );
+}
+
+#[test]
+fn test_list_errors() {
+    let error = run_err("list_range", "pink xs = [1];\nshine xs[1];\n");
+    assert_eq!(
+        error,
+        "List index 1 out of range for a list of length 1.\n[line 2] in script\n"
+    );
+
+    let error = run_err("list_negative", "pink xs = [1];\nxs[-2] = 0;\n");
+    assert!(error.starts_with("List index -2 out of range"));
+
+    let error = run_err("list_fraction", "shine [1][0.5];\n");
+    assert!(error.starts_with("List index must be an integer."));
+
+    let error = run_err("list_not_list", "pink a = 1;\nshine a[0];\n");
+    assert!(error.starts_with("Only lists and maps can be indexed."));
+
+    let error = run_err("list_pop_empty", "pop([]);\n");
+    assert!(error.starts_with("Can't pop from an empty list."));
+}
+
+#[test]
+fn test_maps() {
+    let output = run(
+        "maps",
+        "pink band = {\"roger\": 1, \"david\": 2, 3: true, money: \"none\"};
+        shine band[\"david\"] + band[\"roger\"];
+        band[\"nick\"] = 4;
+        band[\"roger\"] = 5;
+        shine band;
+        shine has(band, \"nick\");
+        shine has(band, \"syd\");
+        shine remove(band, \"david\");
+        shine band[money];
+        band[0] = \"zero\";
+        shine band[-0];
+        pink names = keys(band);
+        time (pink i = 0; i < len(names); i = i + 1) {
+            shine names[i];
+        }
+        shine {\"list\": [1, {}]};
+        {
+            shine \"block\";
+        }
+        ",
+    );
+    assert_eq!(
+        output,
+        "3
+{\"roger\": 5, \"david\": 2, 3: true, Nil: \"none\", \"nick\": 4}
+true
+false
+2
+none
+zero
+roger
+3
+Nil
+nick
+0
+{\"list\": [1, {}]}
+block
+"
+    );
+}
+
+#[test]
+fn test_map_errors() {
+    let error = run_err("map_missing", "pink m = {};\nshine m[\"syd\"];\n");
+    assert_eq!(error, "Undefined key 'syd'.\n[line 2] in script\n");
+
+    let error = run_err("map_unhashable", "pink m = {[1]: 2};\n");
+    assert!(error.starts_with("Map keys must be strings, numbers, booleans or money."));
+
+    let error = run_err("map_has_unhashable", "has({}, {});\n");
+    assert!(error.starts_with("Map keys must be strings, numbers, booleans or money."));
+}
+
+#[test]
+fn test_increment_decrement() {
+    let output = run(
+        "increment",
+        "pink a = 1;
+        shine a++;
+        shine a;
+        shine ++a;
+        shine a--;
+        shine --a;
+        time (pink i = 0; i < 3; i++) {
+            shine i;
+        }
+        brick f() {
+            pink n = 10;
+            brick g() {
+                n++;
+                goodbye n;
+            }
+            shine n--;
+            shine g() + ++n;
+        }
+        f();
+        pink b = 5;
+        shine a + b++;
+        shine -b--;
+        shine b;
+        ",
+    );
+    assert_eq!(output, "1\n2\n3\n3\n1\n0\n1\n2\n10\n21\n6\n-6\n5\n");
+}
+
+#[test]
+fn test_invalid_increment_target() {
+    for source in [
+        "1++;",
+        "++1;",
+        "pink a = 1;\n(a)--;",
+        "pink a = 1;\na++ ++;",
+    ] {
+        let error = run_err("increment_target", source);
+        assert!(
+            error.ends_with("Invalid increment target.\n"),
+            "{}: {}",
+            source,
+            error
+        );
+    }
+}
+
+#[test]
+fn test_readme_conditionals() {
+    let output = run(
+        "readme_if",
+        "outThere?(1 == 1) {
+    shine \"Hey you\";
+}
+",
+    );
+    assert_eq!(output, "Hey you\n");
+
+    let output = run(
+        "readme_else",
+        "outThere?(1 == 2) {
+    shine \"Hey you\";
+} anybody {
+    shine \"Not you\";
+}
+",
+    );
+    assert_eq!(output, "Not you\n");
+
+    let output = run(
+        "readme_else_if",
+        "outThere?(1 == 2) {
+    shine \"Hey you\";
+} anybody outThere?(1 == 1) {
+    shine \"Not you\";
+} anybody {
+    shine \"??\";
+}
+",
+    );
+    assert_eq!(output, "Not you\n");
+}
+
+#[test]
+fn test_else_if_chain() {
+    let output = run(
+        "else_if_chain",
+        "brick grade(n) {
+            outThere?(n < 1) goodbye \"none\";
+            anybody outThere?(n < 2) goodbye \"one\";
+            anybody outThere?(n < 3) goodbye \"two\";
+            anybody outThere?(n < 4) goodbye \"three\";
+            anybody goodbye \"many\";
+        }
+        time (pink i = 0; i < 6; i++) shine grade(i);
+        outThere?(false) shine 1; anybody outThere?(false) shine 2;
+        shine \"end\";
+        ",
+    );
+    assert_eq!(output, "none\none\ntwo\nthree\nmany\nmany\nend\n");
+
+    // Long chains compile without nesting the parser
+    let mut source = String::from("pink x = 150;\noutThere?(x == 0) shine 0;\n");
+    for i in 1..200 {
+        source.push_str(&format!("anybody outThere?(x == {}) shine {};\n", i, i));
+    }
+    assert_eq!(run("long_else_if_chain", &source), "150\n");
+}
+
+#[test]
+fn test_question_mark_outside_keyword() {
+    let error = run_err("question_mark", "pink a? = 1;");
+    assert!(error.contains("Unexpected character"), "{}", error);
+
+    // Without the '?' it is an ordinary name
+    let output = run("out_there_name", "pink outThere = 1;\nshine outThere;\n");
+    assert_eq!(output, "1\n");
+}
+
+#[test]
+fn test_integers() {
+    let output = run(
+        "integers",
+        "shine 3;
+        shine 3.0;
+        shine 1 + 2;
+        shine 1 + 2.0;
+        shine 7 / 2;
+        shine 6 / 2;
+        shine 2 ^ 10;
+        shine 2 ^ -1;
+        shine 2.0 ^ 2;
+        shine -(4 * 5 - 1);
+        shine 9007199254740993 + 0;
+        shine 1 << 62;
+        shine -8 >> 1;
+        shine 4.0 << 1;
+        shine 1 == 1.0;
+        shine 2 < 2.5;
+        pink m = {1: \"one\"};
+        shine m[1.0];
+        shine [10, 20, 30][2.0];
+        shine len(\"abc\");
+        ",
+    );
+    assert_eq!(
+        output,
+        "3\n3.0\n3\n3.0\n3.5\n3.0\n1024\n0.5\n4.0\n-19\n9007199254740993\n\
+         4611686018427387904\n-4\n8\ntrue\ntrue\none\n30\n3\n"
+    );
+}
+
+#[test]
+fn test_integer_errors() {
+    for source in [
+        "shine 9223372036854775807 + 1;",
+        "shine -9223372036854775807 - 2;",
+        "shine 4294967296 * 4294967296;",
+        "shine 2 ^ 63;",
+        "shine 1 << 63;",
+    ] {
+        let error = run_err("integer_overflow", source);
+        assert!(
+            error.starts_with("Integer overflow."),
+            "{}: {}",
+            source,
+            error
+        );
+    }
+
+    let error = run_err("fractional_shift", "shine 1.5 << 1;");
+    assert!(
+        error.starts_with("Operands of a bit shift must be integers."),
+        "{}",
+        error
+    );
+    let error = run_err("negative_shift", "shine 1 >> -1;");
+    assert!(
+        error.starts_with("Shift amount must be between 0 and 63."),
+        "{}",
+        error
+    );
+    let error = run_err("large_literal", "shine 9223372036854775808;");
+    assert!(error.contains("Integer literal is too large."), "{}", error);
+}
+
+#[test]
+fn test_modulo_and_bitwise_operators() {
+    let output = run(
+        "bitwise",
+        "shine 7 % 3;
+        shine -7 % 3;
+        shine 7 % -3;
+        shine 7.5 % 2;
+        shine -1.5 % 1;
+        shine 12 & 10;
+        shine 12 | 10;
+        shine 12 ~ 10;
+        shine ~5;
+        shine ~-1;
+        shine 8.0 & 12;
+        shine 1 | 2 ~ 3 & 4;
+        shine 1 + 2 & 3;
+        shine 6 & 3 == 2;
+        shine 2 * 7 % 4;
+        shine -~0;
+        ",
+    );
+    assert_eq!(
+        output,
+        "1\n2\n-2\n1.5\n0.5\n8\n14\n6\n-6\n0\n8\n3\n3\ntrue\n2\n1\n"
+    );
+}
+
+#[test]
+fn test_bitwise_errors() {
+    for source in ["shine 1.5 & 1;", "shine 1 | \"one\";", "shine 2 ~ 0.5;"] {
+        let error = run_err("bitwise_operands", source);
+        assert!(
+            error.starts_with("Operands must be integers."),
+            "{}: {}",
+            source,
+            error
+        );
+    }
+
+    let error = run_err("bit_not_operand", "shine ~0.5;");
+    assert!(
+        error.starts_with("Operand must be an integer."),
+        "{}",
+        error
+    );
+    let error = run_err("modulo_by_zero", "shine 1 % 0;");
+    assert!(error.starts_with("Modulo by zero."), "{}", error);
+    let error = run_err("modulo_operand", "shine \"a\" % 2;");
+    assert!(error.starts_with("Operand must be a number"), "{}", error);
+}
+
+#[test]
+fn test_power_precedence() {
+    let output = run(
+        "power",
+        "shine 2 + 3 ^ 2;
+        shine 2 * 3 ^ 2;
+        shine 2 ^ 3 ^ 2;
+        shine (2 ^ 3) ^ 2;
+        shine -2 ^ 2;
+        shine (-2) ^ 2;
+        shine 2 ^ -1;
+        shine 2 ^ -1 ^ 2;
+        shine -2 ^ 2 * 3;
+        shine ~1 ^ 2;
+        pink xs = [3];
+        shine xs[0] ^ 2;
+        ",
+    );
+    assert_eq!(output, "11\n18\n512\n64\n-4\n4\n0.5\n0.5\n-12\n-2\n9\n");
+}
+
+#[test]
+fn test_compound_assignment() {
+    let output = run(
+        "compound_assignment",
+        "pink total = 10;
+        total += 5;
+        shine total;
+        total -= 3; // twelve
+        shine total;
+        total *= 2;
+        shine total;
+        total /= 8;
+        shine total;
+        pink base = 3;
+        base ^= 2;
+        shine base;
+        shine base += 1;
+        brick sum(n) {
+            pink acc = 0;
+            time (pink i = 1; i <= n; i++) {
+                acc += i;
+            }
+            brick twice() {
+                acc *= 2;
+            }
+            twice();
+            goodbye acc;
+        }
+        shine sum(4);
+        pink calls = 0;
+        brick next() {
+            calls++;
+            goodbye 1;
+        }
+        pink x = 0;
+        x += next() + next();
+        shine x;
+        shine calls;
+        pink text = \"Hey\";
+        text += \" you\";
+        shine text;
+        ",
+    );
+    assert_eq!(output, "15\n12\n24\n3.0\n9\n10\n20\n2\n2\nHey you\n");
+}
 
 #[test]
-fn test_expressions() {}
+fn test_invalid_compound_assignment_target() {
+    for source in [
+        "pink a = 1;\npink b = 2;\na + b += 1;",
+        "1 -= 1;",
+        "pink xs = [1];\nxs[0] *= 2;",
+    ] {
+        let error = run_err("compound_target", source);
+        assert!(
+            error.contains("Invalid assignment target."),
+            "{}: {}",
+            source,
+            error
+        );
+    }
+}